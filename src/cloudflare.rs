@@ -18,48 +18,271 @@
  ** along with this program. If not, see <https://www.gnu.org/licenses/>.
  */
 const DEFAULT_TIMEOUT: u64 = 5;
+const DEFAULT_REQUEST_TIMEOUT: u64 = 30;
 const RELAY_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
 mod api {
 
-    use super::{ApiError, DEFAULT_TIMEOUT};
+    use super::{ApiError, DEFAULT_REQUEST_TIMEOUT, DEFAULT_TIMEOUT};
     use crate::cloudflare::RELAY_USER_AGENT;
-    use crate::datastructures::{Config, PostData, Relay, RelayConfig, ZoneMapper};
+    use crate::datastructures::{
+        Config, ConfigError, IdentifierMode, MissingHeader, OwnershipCheck, PostData, Provider,
+        Relay, RelayConfig, ZoneMapper,
+    };
+    use crate::provider::DnsProvider;
     use anyhow::anyhow;
-    use log::{error, info};
+    use axum::http::StatusCode;
+    use ipnet::IpNet;
+    use log::{debug, error, info, log, warn, Level};
+    use ring::hmac;
     use serde_derive::{Deserialize, Serialize};
-    use std::collections::HashMap;
-    use std::time::Duration;
+    use serde_json::json;
+    use std::collections::{BTreeMap, HashMap, VecDeque};
+    use std::net::IpAddr;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
     use tap::TapFallible;
+    use tokio::io::AsyncWriteExt;
+    use tokio::sync::{Semaphore, SemaphorePermit};
 
     const CLOUDFLARE_API_PREFIX: &str = "https://api.cloudflare.com/client/v4";
+    const RELAY_RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+    const RELAY_RETRY_MAX_DELAY: Duration = Duration::from_secs(300);
+    const CF_PERMIT_TIMEOUT: Duration = Duration::from_secs(10);
+    const RATE_LIMIT_MAX_RETRIES: u32 = 3;
+    const RATE_LIMIT_DEFAULT_DELAY: Duration = Duration::from_secs(1);
+    const RATE_LIMIT_MAX_DELAY: Duration = Duration::from_secs(60);
+    const RATE_LIMIT_WARNING_THRESHOLD: u64 = 50;
+    const DNS_RECORDS_PER_PAGE: u32 = 100;
+    // Override api prefix, point to mock server in tests
+    const CLOUDFLARE_API_PREFIX_ENV: &str = "CLOUDFLARE_API_PREFIX";
+
+    // Configure file first, then environment variable, then default prefix
+    fn api_prefix(api_base: &Option<String>) -> String {
+        api_base
+            .clone()
+            .or_else(|| std::env::var(CLOUDFLARE_API_PREFIX_ENV).ok())
+            .map(|s| s.trim_end_matches('/').to_string())
+            .unwrap_or_else(|| CLOUDFLARE_API_PREFIX.to_string())
+    }
 
-    pub const DEFAULT_COLUMN: &'static str = "X-Real-IP";
+    pub const DEFAULT_COLUMN: &str = "X-Real-IP";
+    pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+    // Always redacted in query route
+    const DEFAULT_REDACT_HEADERS: [&str; 4] = [
+        "authorization",
+        "proxy-authorization",
+        "cookie",
+        "x-signature",
+    ];
 
-    #[derive(Clone, Debug, Deserialize)]
+    const RECORD_TYPES: [&str; 2] = ["A", "AAAA"];
+
+    fn record_type(ip: &IpAddr) -> &'static str {
+        match ip {
+            IpAddr::V4(_) => "A",
+            IpAddr::V6(_) => "AAAA",
+        }
+    }
+
+    // State file is `{"<uuid>": {"A": "<ip>", "AAAA": "<ip>"}}`, missing or corrupt file is
+    // treated as empty state
+    fn load_state(path: Option<&str>) -> HashMap<(String, &'static str), String> {
+        let Some(path) = path else {
+            return Default::default();
+        };
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Default::default(),
+            Err(e) => {
+                warn!("Unable read state file {:?}, ignored: {}", path, e);
+                return Default::default();
+            }
+        };
+        let Ok(state) = serde_json::from_str::<HashMap<String, HashMap<String, String>>>(&content)
+            .tap_err(|e| warn!("State file {:?} is corrupt, ignored: {}", path, e))
+        else {
+            return Default::default();
+        };
+        state
+            .into_iter()
+            .flat_map(|(uuid, records)| {
+                records.into_iter().filter_map(move |(record_type, ip)| {
+                    let record_type = RECORD_TYPES.into_iter().find(|t| t.eq(&record_type))?;
+                    Some(((uuid.clone(), record_type), ip))
+                })
+            })
+            .collect()
+    }
+
+    // IPv4-mapped IPv6 address (e.g. `::ffff:203.0.113.5`) from proxy is unwrapped to IPv4
+    fn parse_ip(ip: &str) -> Option<IpAddr> {
+        ip.parse::<IpAddr>().ok().map(|ip| ip.to_canonical())
+    }
+
+    // Private, loopback, link-local and other reserved addresses should not be published
+    fn is_public_ip(ip: &IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => {
+                let [a, b, _, _] = ip.octets();
+                !(ip.is_private()
+                    || ip.is_loopback()
+                    || ip.is_link_local()
+                    || ip.is_unspecified()
+                    || ip.is_broadcast()
+                    || ip.is_documentation()
+                    || ip.is_multicast()
+                    // Shared address space (CGNAT) 100.64.0.0/10
+                    || (a == 100 && (b & 0b1100_0000) == 64)
+                    // Benchmarking 198.18.0.0/15
+                    || (a == 198 && (b & 0xfe) == 18)
+                    // Reserved 240.0.0.0/4
+                    || a >= 240)
+            }
+            IpAddr::V6(ip) => {
+                if let Some(v4) = ip.to_ipv4_mapped() {
+                    return is_public_ip(&IpAddr::V4(v4));
+                }
+                let segments = ip.segments();
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // Unique local fc00::/7
+                    || (segments[0] & 0xfe00) == 0xfc00
+                    // Link-local fe80::/10
+                    || (segments[0] & 0xffc0) == 0xfe80
+                    // Documentation 2001:db8::/32
+                    || (segments[0] == 0x2001 && segments[1] == 0xdb8))
+            }
+        }
+    }
+
+    // Retry on 429 with delay from `Retry-After` header, warn when quota is running out
+    async fn send_request(
+        builder: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, reqwest::Error> {
+        let mut attempts = 0;
+        loop {
+            // Request with streaming body can not be retried
+            let Some(request) = builder.try_clone() else {
+                return builder.send().await;
+            };
+            let resp = request.send().await?;
+            check_rate_limit_remaining(resp.headers());
+            if resp.status() != reqwest::StatusCode::TOO_MANY_REQUESTS
+                || attempts >= RATE_LIMIT_MAX_RETRIES
+            {
+                return Ok(resp);
+            }
+            attempts += 1;
+            let delay = resp
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(RATE_LIMIT_DEFAULT_DELAY)
+                .min(RATE_LIMIT_MAX_DELAY);
+            warn!(
+                "Cloudflare rate limit exceeded, retry after {:?} ({}/{})",
+                delay, attempts, RATE_LIMIT_MAX_RETRIES
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    fn check_rate_limit_remaining(headers: &reqwest::header::HeaderMap) {
+        let value = |name| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse::<u64>().ok())
+        };
+        let Some(remaining) = value("x-ratelimit-remaining") else {
+            return;
+        };
+        let threshold = value("x-ratelimit-limit")
+            .map(|limit| limit / 10)
+            .unwrap_or(RATE_LIMIT_WARNING_THRESHOLD);
+        if remaining <= threshold {
+            warn!(
+                "Cloudflare rate limit is approaching, {} remaining",
+                remaining
+            );
+        }
+    }
+
+    // Only first segment of uuid is shown in log
+    fn redact_uuid(uuid: &str) -> String {
+        format!("{}-****", uuid.split('-').next().unwrap_or_default())
+    }
+
+    // Hide password in url (e.g. relay target with basic auth)
+    fn redact_url(url: &str) -> String {
+        match reqwest::Url::parse(url) {
+            Ok(mut url) if url.password().is_some() => {
+                url.set_password(Some("***")).ok();
+                url.to_string()
+            }
+            _ => url.to_string(),
+        }
+    }
+
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
     pub struct DNSRecord {
         id: String,
+        #[serde(rename = "type")]
+        type_: String,
         zone_id: String,
         name: String,
         content: String,
         proxied: bool,
         ttl: i32,
+        #[serde(default)]
+        comment: Option<String>,
+        // MX only
+        #[serde(default)]
+        priority: Option<u16>,
+        #[serde(default)]
+        modified_on: Option<String>,
     }
 
     impl DNSRecord {
-        async fn update_ns_record(&self, session: &reqwest::Client) -> anyhow::Result<bool> {
-            let resp = session
-                .put(
-                    format!(
-                        "{}/zones/{}/dns_records/{}",
-                        CLOUDFLARE_API_PREFIX, &self.zone_id, &self.id
+        // Return false if Cloudflare kept the record unmodified, i.e. redundant update
+        async fn update_ns_record(
+            &self,
+            session: &reqwest::Client,
+            api_base: &str,
+            token: &str,
+        ) -> Result<bool, ApiError> {
+            let resp = send_request(
+                session
+                    .put(
+                        format!(
+                            "{}/zones/{}/dns_records/{}",
+                            api_base,
+                            self.zone_id(),
+                            self.id()
+                        )
+                        .as_str(),
                     )
-                    .as_str(),
-                )
-                .json(&PutDNSRecord::from(self))
-                .send()
-                .await
-                .map_err(|e| anyhow!("Got error while update DNS record: {:?}", e))?;
-            Ok(resp.status().is_success())
+                    .json(&PutDNSRecord::from(self))
+                    .bearer_auth(token),
+            )
+            .await
+            .map_err(|e| anyhow!("Got error while update DNS record: {:?}", e))?;
+            let result = CloudFlareResult::from_response(resp).await?.result();
+            let modified_on = result.get("modified_on").and_then(|v| v.as_str());
+            Ok(self.modified_on.is_none()
+                || modified_on.is_none()
+                || self.modified_on.as_deref() != modified_on)
+        }
+
+        pub fn id(&self) -> &str {
+            &self.id
+        }
+
+        pub fn zone_id(&self) -> &str {
+            &self.zone_id
         }
 
         pub fn name(&self) -> &str {
@@ -80,45 +303,229 @@ mod api {
 
         pub async fn fetch_dns_record(
             client: &reqwest::Client,
+            api_base: &str,
+            token: &str,
             zone: &str,
             name: &str,
-        ) -> anyhow::Result<Self> {
-            let resp = client
-                .get(format!(
-                    "{}/zones/{}/dns_records",
-                    CLOUDFLARE_API_PREFIX, zone
-                ))
-                .query(
-                    &[("type", "A"), ("name", name)]
-                        .iter()
-                        .map(|(x, y)| (x.to_string(), y.to_string()))
-                        .collect::<HashMap<String, String>>(),
+            record_type: &str,
+        ) -> Result<Option<Self>, ApiError> {
+            let mut records: Vec<Self> = Vec::new();
+            let mut page = 1;
+            loop {
+                let resp = send_request(
+                    client
+                        .get(format!("{}/zones/{}/dns_records", api_base, zone))
+                        // Wildcard name is kept as is (`*` is not percent-encoded in query string)
+                        .query(
+                            &[
+                                ("type", record_type.to_string()),
+                                ("name", name.to_lowercase()),
+                                ("page", page.to_string()),
+                                ("per_page", DNS_RECORDS_PER_PAGE.to_string()),
+                            ]
+                            .into_iter()
+                            .map(|(x, y)| (x.to_string(), y))
+                            .collect::<HashMap<String, String>>(),
+                        )
+                        .bearer_auth(token),
                 )
-                .send()
                 .await
                 .map_err(|e| anyhow!("Got error while query DNS records: {:?}", e))?;
-            if !resp.status().is_success() {
-                return Err(anyhow!("Api request is unsuccessful: {:?}", resp));
+                let resp = CloudFlareResult::from_response(resp).await?;
+                let total_pages = resp.result_info().map(|info| info.total_pages());
+                let result = serde_json::from_value::<Vec<_>>(resp.result())
+                    .map_err(|e| anyhow!("Got error while serialize DNS result: {:?}", e))?;
+                let is_empty = result.is_empty();
+                records.extend(result);
+                // Response without result_info is treated as single page
+                if is_empty || total_pages.is_none_or(|total| page >= total) {
+                    break;
+                }
+                page += 1;
             }
-            let resp: CloudFlareResult = resp
-                .json()
-                .await
-                .map_err(|e| anyhow!("Got error while serialize DNS records: {:?}", e))?;
-            if !resp.success() {
-                return Err(anyhow!(
-                    "Got error in cloudflare dns api request: {:?}",
-                    resp.errors()
-                ));
+            if records.len() > 1 {
+                debug!(
+                    "Found {} {} records of {}, last one is used",
+                    records.len(),
+                    record_type,
+                    name
+                );
             }
-            serde_json::from_value::<Vec<_>>(resp.result())
-                .map_err(|e| anyhow!("Got error while serialize DNS result: {:?}", e))?
-                .pop()
-                .ok_or(anyhow!("Result is empty!"))
+            Ok(records.pop())
+        }
+
+        pub async fn fetch_dns_record_by_id(
+            client: &reqwest::Client,
+            api_base: &str,
+            token: &str,
+            zone: &str,
+            id: &str,
+        ) -> Result<Self, ApiError> {
+            let resp = send_request(
+                client
+                    .get(format!("{}/zones/{}/dns_records/{}", api_base, zone, id))
+                    .bearer_auth(token),
+            )
+            .await
+            .map_err(|e| anyhow!("Got error while query DNS record: {:?}", e))?;
+            let resp = CloudFlareResult::from_response(resp).await?;
+            Ok(serde_json::from_value(resp.result())
+                .map_err(|e| anyhow!("Got error while serialize DNS result: {:?}", e))?)
+        }
+
+        async fn create_ns_record(
+            session: &reqwest::Client,
+            api_base: &str,
+            token: &str,
+            zone: &str,
+            record: &PutDNSRecord,
+        ) -> Result<(), ApiError> {
+            let resp = send_request(
+                session
+                    .post(format!("{}/zones/{}/dns_records", api_base, zone))
+                    .json(record)
+                    .bearer_auth(token),
+            )
+            .await
+            .map_err(|e| anyhow!("Got error while create DNS record: {:?}", e))?;
+            CloudFlareResult::from_response(resp).await.map(|_| ())
+        }
+
+        async fn delete_ns_record(
+            &self,
+            session: &reqwest::Client,
+            api_base: &str,
+            token: &str,
+        ) -> Result<(), ApiError> {
+            let resp = send_request(
+                session
+                    .delete(format!(
+                        "{}/zones/{}/dns_records/{}",
+                        api_base,
+                        self.zone_id(),
+                        self.id()
+                    ))
+                    .bearer_auth(token),
+            )
+            .await
+            .map_err(|e| anyhow!("Got error while delete DNS record: {:?}", e))?;
+            CloudFlareResult::from_response(resp).await.map(|_| ())
         }
 
         pub fn set_content(&mut self, content: String) {
             self.content = content;
         }
+
+        pub fn set_comment(&mut self, comment: String) {
+            self.comment = Some(comment);
+        }
+
+        pub fn priority(&self) -> Option<u16> {
+            self.priority
+        }
+
+        pub fn set_priority(&mut self, priority: u16) {
+            self.priority = Some(priority);
+        }
+    }
+
+    struct Cloudflare<'a> {
+        client: &'a reqwest::Client,
+        api_base: &'a str,
+        create_missing_records: bool,
+        default_ttl: i32,
+        default_proxied: bool,
+    }
+
+    impl DnsProvider for Cloudflare<'_> {
+        type Record = DNSRecord;
+
+        fn name(&self) -> &'static str {
+            "cloudflare"
+        }
+
+        async fn fetch(
+            &self,
+            zone: &ZoneMapper,
+            record_type: &str,
+        ) -> Result<Option<DNSRecord>, ApiError> {
+            if let Some(id) = zone.record_id() {
+                let record = DNSRecord::fetch_dns_record_by_id(
+                    self.client,
+                    self.api_base,
+                    zone.token(),
+                    zone.zone(),
+                    id,
+                )
+                .await?;
+                if record.type_.eq(record_type) {
+                    return Ok(Some(record));
+                }
+                // e.g. dual-stack client, record ID only pins record of one type
+                debug!(
+                    "Record {} of {} is {}, search {} record by name",
+                    id,
+                    zone.domain(),
+                    record.type_,
+                    record_type
+                );
+            }
+            DNSRecord::fetch_dns_record(
+                self.client,
+                self.api_base,
+                zone.token(),
+                zone.zone(),
+                zone.domain(),
+                record_type,
+            )
+            .await
+        }
+
+        fn content(record: &DNSRecord) -> &str {
+            record.content()
+        }
+
+        async fn update(
+            &self,
+            zone: &ZoneMapper,
+            record_type: &str,
+            record: Option<DNSRecord>,
+            content: &str,
+        ) -> Result<bool, ApiError> {
+            let Some(mut record) = record else {
+                if !self.create_missing_records {
+                    warn!(
+                        "Record {} not found, set create_missing_records to create it",
+                        zone.domain()
+                    );
+                    return Err(ApiError::NotFound);
+                }
+                info!("Record {} not found, create new record", zone.domain());
+                return DNSRecord::create_ns_record(
+                    self.client,
+                    self.api_base,
+                    zone.token(),
+                    zone.zone(),
+                    &PutDNSRecord::new(
+                        record_type.to_string(),
+                        zone.domain().to_string(),
+                        content.to_string(),
+                        self.default_proxied,
+                        self.default_ttl,
+                        zone.comment().clone(),
+                    ),
+                )
+                .await
+                .map(|_| true);
+            };
+            record.set_content(content.to_string());
+            if let Some(comment) = zone.comment() {
+                record.set_comment(comment.clone());
+            }
+            record
+                .update_ns_record(self.client, self.api_base, zone.token())
+                .await
+        }
     }
 
     #[derive(Clone, Debug, Serialize)]
@@ -129,35 +536,116 @@ mod api {
         content: String,
         proxied: bool,
         ttl: i32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        comment: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        priority: Option<u16>,
+    }
+
+    impl PutDNSRecord {
+        fn new(
+            type_: String,
+            name: String,
+            content: String,
+            proxied: bool,
+            ttl: i32,
+            comment: Option<String>,
+        ) -> Self {
+            Self {
+                type_,
+                name,
+                content,
+                proxied,
+                ttl,
+                comment,
+                priority: None,
+            }
+        }
+
+        fn set_priority(mut self, priority: u16) -> Self {
+            self.priority = Some(priority);
+            self
+        }
     }
 
     impl From<&DNSRecord> for PutDNSRecord {
         fn from(dns_record: &DNSRecord) -> Self {
             Self {
-                type_: 'A'.to_string(),
+                type_: dns_record.type_.clone(),
                 name: dns_record.name().to_string(),
                 content: dns_record.content().to_string(),
                 proxied: dns_record.proxied(),
                 ttl: dns_record.ttl(),
+                comment: dns_record.comment.clone(),
+                priority: dns_record.priority,
+            }
+        }
+    }
+
+    // Record returned by lookup, cloudflare identifiers (zone_id, id) are not exposed
+    #[derive(Clone, Debug, Serialize)]
+    pub struct LookupRecord {
+        name: String,
+        #[serde(rename = "type")]
+        type_: String,
+        content: String,
+        ttl: i32,
+        proxied: bool,
+    }
+
+    impl From<DNSRecord> for LookupRecord {
+        fn from(dns_record: DNSRecord) -> Self {
+            Self {
+                name: dns_record.name,
+                type_: dns_record.type_,
+                content: dns_record.content,
+                ttl: dns_record.ttl,
+                proxied: dns_record.proxied,
             }
         }
     }
 
-    #[allow(dead_code)]
     #[derive(Clone, Debug, Deserialize)]
     pub struct CloudFlareError {
         code: i64,
         message: String,
     }
 
+    impl CloudFlareError {
+        pub fn code(&self) -> i64 {
+            self.code
+        }
+
+        pub fn message(&self) -> &str {
+            &self.message
+        }
+    }
+
+    #[derive(Clone, Debug, Deserialize)]
+    pub struct ResultInfo {
+        total_pages: u32,
+    }
+
+    impl ResultInfo {
+        pub fn total_pages(&self) -> u32 {
+            self.total_pages
+        }
+    }
+
     #[derive(Clone, Debug, Deserialize)]
     pub struct CloudFlareResult {
         success: bool,
         result: serde_json::Value,
         errors: Vec<CloudFlareError>,
+        #[serde(default)]
+        result_info: Option<ResultInfo>,
     }
 
     impl CloudFlareResult {
+        pub fn result_info(&self) -> Option<&ResultInfo> {
+            self.result_info.as_ref()
+        }
+
         pub fn success(&self) -> bool {
             self.success
         }
@@ -169,173 +657,1319 @@ mod api {
         pub fn errors(&self) -> &Vec<CloudFlareError> {
             &self.errors
         }
+
+        async fn from_response(resp: reqwest::Response) -> Result<Self, ApiError> {
+            let status = resp.status();
+            let resp: Self = resp.json().await.map_err(|e| {
+                anyhow!(
+                    "Got error while serialize cloudflare response ({}): {:?}",
+                    status,
+                    e
+                )
+            })?;
+            if !resp.success() {
+                return Err(match resp.errors().first() {
+                    Some(error) => ApiError::cloudflare(error.code(), error.message().to_string()),
+                    None => anyhow!("Api request is unsuccessful: {}", status).into(),
+                });
+            }
+            Ok(resp)
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    pub struct MappedClient {
+        zones: Vec<ZoneMapper>,
+        allowed_cidrs: Vec<IpNet>,
+        // Empty means detect by submitted IP
+        record_types: Vec<String>,
+        column: Option<Vec<String>>,
+        hmac_key: Option<hmac::Key>,
+        providers: Vec<Provider>,
+    }
+
+    impl MappedClient {
+        pub fn zones(&self) -> &Vec<ZoneMapper> {
+            &self.zones
+        }
+
+        pub fn providers(&self) -> &Vec<Provider> {
+            &self.providers
+        }
+
+        pub fn accept_record_type(&self, record_type: &str) -> bool {
+            self.record_types.is_empty() || self.record_types.iter().any(|t| t.eq(record_type))
+        }
+
+        pub fn is_allowed(&self, ip: &str) -> bool {
+            if self.allowed_cidrs.is_empty() {
+                return true;
+            }
+            parse_ip(ip)
+                .map(|ip| self.allowed_cidrs.iter().any(|net| net.contains(&ip)))
+                .unwrap_or_default()
+        }
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum UpdateStatus {
+        Updated,
+        Unchanged,
+        // Relay forward failed and queued for retry
+        Queued,
+    }
+
+    // Result of single zone, error is kind only unless `expose_api_error` is set
+    #[derive(Clone, Debug, Serialize)]
+    pub struct ZoneResult {
+        domain: String,
+        provider: &'static str,
+        updated: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    }
+
+    impl ZoneResult {
+        fn new(domain: &str, provider: &'static str, updated: bool, error: Option<String>) -> Self {
+            Self {
+                domain: domain.to_string(),
+                provider,
+                updated,
+                error,
+            }
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    pub struct UpdateResult {
+        status: UpdateStatus,
+        // Empty in relay mode
+        zones: Vec<ZoneResult>,
+    }
+
+    impl UpdateResult {
+        pub fn status(&self) -> UpdateStatus {
+            self.status
+        }
+        // Combine results of dual-stack update, updated take precedence over queued
+        pub fn merge(mut self, other: Self) -> Self {
+            self.status = match (self.status, other.status) {
+                (UpdateStatus::Updated, _) | (_, UpdateStatus::Updated) => UpdateStatus::Updated,
+                (UpdateStatus::Queued, _) | (_, UpdateStatus::Queued) => UpdateStatus::Queued,
+                _ => UpdateStatus::Unchanged,
+            };
+            self.zones.extend(other.zones);
+            self
+        }
+        pub fn zones(&self) -> &Vec<ZoneResult> {
+            &self.zones
+        }
+    }
+
+    impl From<UpdateStatus> for UpdateResult {
+        fn from(status: UpdateStatus) -> Self {
+            Self {
+                status,
+                zones: Vec::new(),
+            }
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    struct QueuedForward {
+        target: String,
+        ip: String,
+        request_id: String,
+        created: Instant,
+        next_retry: Instant,
+        attempts: u32,
+    }
+
+    #[derive(Clone, Debug)]
+    struct UpdateHistory {
+        last_update: Instant,
+        // Content before and after last update
+        recent: [String; 2],
     }
 
     #[derive(Clone, Debug)]
     pub struct ApiRequest {
-        mapper: HashMap<String, Vec<ZoneMapper>>,
-        relay: Relay,
+        mapper: HashMap<String, MappedClient>,
+        relay: Arc<Relay>,
+        client: reqwest::Client,
+        column: Vec<String>,
+        column_ipv6: Vec<String>,
+        // Lowercase header names
+        redact_headers: Vec<String>,
+        batch_concurrency: usize,
+        api_base: String,
+        create_missing_records: bool,
+        default_ttl: i32,
+        default_proxied: bool,
+        use_peer_addr: bool,
+        expose_api_error: bool,
+        opaque_unknown_client: bool,
+        identifier_mode: IdentifierMode,
+        allow_private_ips: bool,
+        allow_lookup: bool,
+        admin_token: Option<String>,
+        // Limit concurrent requests to cloudflare
+        cf_permits: Arc<Semaphore>,
+        request_timeout: Duration,
+        update_log_level: Level,
+        unchanged_log_level: Level,
+        min_update_interval: Option<Duration>,
+        ownership_check: Option<OwnershipCheck>,
+        managed_name_prefix: Option<String>,
+        update_history: Arc<Mutex<HashMap<(String, String), UpdateHistory>>>,
+        // Last pushed IP of (uuid, record type), used by reconciliation
+        last_pushed: Arc<Mutex<HashMap<(String, &'static str), String>>>,
+        state_file: Option<String>,
+        // SHA-256 of configure file, empty if not built from configure
+        config_hash: String,
+        // At most one (latest) forward per target
+        relay_queue: Arc<Mutex<VecDeque<QueuedForward>>>,
+    }
+
+    // Parts needed by relay forwarding, all shared, so retry task can forward without
+    // cloning ApiRequest or holding lock across network I/O
+    #[derive(Clone, Debug)]
+    pub struct RelayForwarder {
+        relay: Arc<Relay>,
         client: reqwest::Client,
-        column: String,
+        relay_queue: Arc<Mutex<VecDeque<QueuedForward>>>,
+    }
+
+    impl RelayForwarder {
+        pub async fn process_relay(
+            &self,
+            uuid: &String,
+            new_ip: String,
+            request_id: &str,
+        ) -> Result<(), ApiError> {
+            let data = PostData::new(new_ip);
+            if self.relay.broadcast() {
+                return self.broadcast_relay(uuid, &data, request_id).await;
+            }
+            // Most severe status responded by upstream, None if no upstream is reachable
+            let mut worst: Option<StatusCode> = None;
+            for upstream in self.relay.target() {
+                if let Ok(status) = self
+                    .client
+                    .post(format!("{}{}", upstream, uuid))
+                    // Upstream reuses this ID, so log of all hops can be correlated
+                    .header(REQUEST_ID_HEADER, request_id)
+                    .json(&data)
+                    .send()
+                    .await
+                    .map(|ret| ret.status())
+                    .tap_err(|e| error!("{}", e))
+                {
+                    if status.is_success() {
+                        return Ok(());
+                    }
+                    error!(
+                        "Post to {} unsuccessful: {:?} (request {})",
+                        upstream, status, request_id
+                    );
+                    worst = worst.max(Some(status));
+                }
+            }
+            Err(worst.map_or(ApiError::ServiceUnavailable, ApiError::Upstream))
+        }
+
+        // Forward to every target concurrently, so one hung upstream doesn't hold up the others,
+        // succeeded if any target accepted the forward
+        async fn broadcast_relay(
+            &self,
+            uuid: &String,
+            data: &PostData,
+            request_id: &str,
+        ) -> Result<(), ApiError> {
+            let results =
+                futures_util::future::join_all(self.relay.target().iter().map(|upstream| {
+                    let request = self
+                        .client
+                        .post(format!("{}{}", upstream, uuid))
+                        .header(REQUEST_ID_HEADER, request_id)
+                        .timeout(self.relay.target_timeout())
+                        .json(data)
+                        .send();
+                    async move { (upstream, request.await.map(|ret| ret.status())) }
+                }))
+                .await;
+
+            let mut succeeded = Vec::new();
+            let mut failed = Vec::new();
+            let mut timed_out = Vec::new();
+            let mut worst: Option<StatusCode> = None;
+            for (upstream, result) in results {
+                match result {
+                    Ok(status) if status.is_success() => succeeded.push(upstream.as_str()),
+                    Ok(status) => {
+                        error!(
+                            "Post to {} unsuccessful: {:?} (request {})",
+                            upstream, status, request_id
+                        );
+                        worst = worst.max(Some(status));
+                        failed.push(upstream.as_str());
+                    }
+                    Err(e) if e.is_timeout() => timed_out.push(upstream.as_str()),
+                    Err(e) => {
+                        error!("{}", e);
+                        failed.push(upstream.as_str());
+                    }
+                }
+            }
+
+            if failed.is_empty() && timed_out.is_empty() {
+                debug!(
+                    "Broadcast to {} targets succeeded (request {})",
+                    succeeded.len(),
+                    request_id
+                );
+            } else {
+                warn!(
+                    "Broadcast succeeded: {:?}, failed: {:?}, timed out: {:?} (request {})",
+                    succeeded, failed, timed_out, request_id
+                );
+            }
+
+            if !succeeded.is_empty() {
+                return Ok(());
+            }
+            Err(match worst {
+                Some(status) => ApiError::Upstream(status),
+                None if !timed_out.is_empty() => ApiError::GatewayTimeout,
+                None => ApiError::ServiceUnavailable,
+            })
+        }
+
+        // Retry queued forwards which are due, called by background task. Items stay in queue
+        // while retrying, so newer forward of same target can replace or remove them meanwhile
+        pub async fn retry_relay_queue(&self) {
+            let now = Instant::now();
+            let due = {
+                let mut queue = self.relay_queue.lock().unwrap();
+                queue.retain(|item| {
+                    let expired = item.created.elapsed() > self.relay.queue_ttl();
+                    if expired {
+                        warn!(
+                            "Drop queued forward of {} after {} attempts, TTL expired (request {})",
+                            item.target, item.attempts, item.request_id
+                        );
+                    }
+                    !expired
+                });
+                queue
+                    .iter()
+                    .filter(|item| item.next_retry <= now)
+                    .cloned()
+                    .collect::<Vec<_>>()
+            };
+
+            for item in due {
+                let ret = self
+                    .process_relay(&item.target, item.ip.clone(), &item.request_id)
+                    .await;
+                let mut queue = self.relay_queue.lock().unwrap();
+                let Some(position) = queue.iter().position(|queued| {
+                    queued.target == item.target && queued.created == item.created
+                }) else {
+                    // Replaced or removed by newer forward, result is stale
+                    continue;
+                };
+                if ret.is_ok() {
+                    info!(
+                        "Queued forward of {} succeeded after {} retries (request {})",
+                        item.target,
+                        item.attempts + 1,
+                        item.request_id
+                    );
+                    queue.remove(position);
+                    continue;
+                }
+                let queued = &mut queue[position];
+                queued.attempts += 1;
+                queued.next_retry = Instant::now()
+                    + (RELAY_RETRY_BASE_DELAY * 2u32.saturating_pow(queued.attempts))
+                        .min(RELAY_RETRY_MAX_DELAY);
+            }
+        }
     }
 
     impl TryFrom<RelayConfig> for ApiRequest {
-        type Error = anyhow::Error;
+        type Error = ConfigError;
 
         fn try_from(value: RelayConfig) -> Result<Self, Self::Error> {
+            Self::from_relay_config(value, RELAY_USER_AGENT, None)
+        }
+    }
+
+    impl ApiRequest {
+        fn from_relay_config(
+            value: RelayConfig,
+            user_agent: &str,
+            local_address: Option<IpAddr>,
+        ) -> Result<Self, ConfigError> {
             let client = reqwest::ClientBuilder::new()
                 .timeout(Duration::from_secs(DEFAULT_TIMEOUT))
-                .user_agent(RELAY_USER_AGENT);
+                .user_agent(user_agent)
+                .local_address(local_address);
+            let client = if value.connection_reuse() {
+                client
+            } else {
+                client.pool_max_idle_per_host(0)
+            };
+            let client = if value.http2_prior_knowledge() {
+                client.http2_prior_knowledge()
+            } else {
+                client
+            };
             let client = if let Some(proxy) = value.proxy() {
-                client.proxy(
-                    reqwest::Proxy::all(proxy)
-                        .map_err(|e| anyhow!("Parse proxy scheme error: {:?}", e))?,
-                )
+                client.proxy(reqwest::Proxy::all(proxy).map_err(|e| {
+                    ConfigError::Validation(format!("Parse proxy scheme error: {:?}", e))
+                })?)
             } else {
                 client
             }
             .build()
             .unwrap();
-            let relay = Relay::try_from(value)?;
+            let relay = Arc::new(Relay::from(value));
             Ok(Self {
                 mapper: HashMap::new(),
                 relay,
                 client,
-                column: "".to_string(),
+                column: Vec::new(),
+                column_ipv6: Vec::new(),
+                redact_headers: Vec::new(),
+                batch_concurrency: 1,
+                api_base: CLOUDFLARE_API_PREFIX.to_string(),
+                create_missing_records: false,
+                default_ttl: Default::default(),
+                default_proxied: false,
+                use_peer_addr: false,
+                expose_api_error: false,
+                opaque_unknown_client: false,
+                identifier_mode: Default::default(),
+                allow_private_ips: false,
+                allow_lookup: false,
+                admin_token: None,
+                // Relay mode does not request cloudflare
+                cf_permits: Arc::new(Semaphore::new(1)),
+                request_timeout: Duration::from_secs(DEFAULT_REQUEST_TIMEOUT),
+                update_log_level: Level::Info,
+                unchanged_log_level: Level::Debug,
+                min_update_interval: None,
+                ownership_check: None,
+                managed_name_prefix: None,
+                update_history: Default::default(),
+                last_pushed: Default::default(),
+                state_file: None,
+                config_hash: Default::default(),
+                relay_queue: Default::default(),
             })
         }
     }
 
     impl TryFrom<Config> for ApiRequest {
-        type Error = anyhow::Error;
+        type Error = ConfigError;
 
         fn try_from(value: Config) -> Result<Self, Self::Error> {
             let ip_column = value
                 .column_ip()
+                .filter(|columns| !columns.is_empty())
+                .unwrap_or_else(|| vec![DEFAULT_COLUMN.to_string()]);
+            let ipv6_column = value.column_ipv6();
+            let redact_headers = DEFAULT_REDACT_HEADERS
+                .iter()
+                .map(|name| name.to_string())
+                .chain(
+                    value
+                        .redact_headers()
+                        .iter()
+                        .map(|name| name.to_lowercase()),
+                )
+                .collect::<Vec<_>>();
+            let batch_concurrency = value.batch_concurrency();
+            let use_peer_addr = value.use_peer_addr();
+            let opaque_unknown_client = value.opaque_unknown_client();
+            let identifier_mode = value.identifier_mode();
+            let user_agent = value
+                .user_agent()
                 .clone()
-                .unwrap_or_else(|| DEFAULT_COLUMN.to_string());
+                .unwrap_or_else(|| RELAY_USER_AGENT.to_string());
+            let local_address = value.local_address();
+            let admin_token = value.admin_token().clone();
+            let update_log_level = value.update_log_level();
+            let unchanged_log_level = value.unchanged_log_level();
+            let config_hash = value.hash().to_string();
+            // Relay client always use default timeout
+            let timeout = match value.timeout_secs() {
+                Some(timeout) if !value.is_relay_mode() => timeout,
+                _ => DEFAULT_TIMEOUT,
+            };
+            // Default handler timeout follows long client timeout
+            let request_timeout = value
+                .request_timeout_secs()
+                .unwrap_or_else(|| DEFAULT_REQUEST_TIMEOUT.max(timeout + 5));
+            // Handler timeout should not be shorter than single outgoing request
+            if request_timeout <= timeout {
+                return Err(ConfigError::Validation(format!(
+                    "request_timeout_secs should be longer than client timeout ({}s), but got {}",
+                    timeout, request_timeout
+                )));
+            }
+            let request_timeout = Duration::from_secs(request_timeout);
             if value.is_relay_mode() {
-                return Self::try_from(value.relay()).map(|x| x.set_column(ip_column));
+                return Self::from_relay_config(value.relay(), &user_agent, local_address).map(
+                    |x| {
+                        x.set_column(ip_column, ipv6_column)
+                            .set_redact_headers(redact_headers)
+                            .set_batch_concurrency(batch_concurrency)
+                            .set_use_peer_addr(use_peer_addr)
+                            .set_opaque_unknown_client(opaque_unknown_client)
+                            .set_identifier_mode(identifier_mode)
+                            .set_admin_token(admin_token)
+                            .set_request_timeout(request_timeout)
+                            .set_log_level(update_log_level, unchanged_log_level)
+                            .set_config_hash(config_hash)
+                    },
+                );
             }
+            // Authorization header is set per request, since zone may have its own token
             let client = reqwest::ClientBuilder::new()
-                .default_headers({
-                    let mut m = reqwest::header::HeaderMap::new();
-                    m.insert(
-                        "Authorization",
-                        format!("Bearer {}", value.token()).parse().unwrap(),
-                    );
-                    m
-                })
-                .timeout(Duration::from_secs(DEFAULT_TIMEOUT))
-                .build()
-                .unwrap();
+                .timeout(Duration::from_secs(timeout))
+                .user_agent(user_agent)
+                .local_address(local_address);
+            // Fail fast on unreachable endpoint, while slow response can still use whole timeout
+            let client = match value.connect_timeout() {
+                Some(connect_timeout) => client.connect_timeout(connect_timeout),
+                None => client,
+            };
+            let client = if let Some(proxy) = value.proxy() {
+                client.proxy(
+                    reqwest::Proxy::all(proxy)
+                        .map_err(|e| {
+                            ConfigError::Validation(format!("Parse proxy scheme error: {:?}", e))
+                        })?
+                        .no_proxy(
+                            value
+                                .no_proxy()
+                                .as_deref()
+                                .and_then(reqwest::NoProxy::from_string),
+                        ),
+                )
+            } else {
+                client
+            }
+            .build()
+            .unwrap();
             let mut m = HashMap::new();
             let mut zone_map = HashMap::new();
             for zone in value.zones() {
-                zone_map.insert(zone.domain(), zone.zone());
+                // Safety net against mistyped zone ID
+                if !value.allowed_zones().is_empty()
+                    && !value.allowed_zones().iter().any(|id| id.eq(zone.zone()))
+                {
+                    return Err(ConfigError::Validation(format!(
+                        "Zone {:?} of {:?} is not in allowed_zones",
+                        zone.zone(),
+                        zone.domain()
+                    )));
+                }
+                // DNS names are case-insensitive, same domain in different zones (e.g. split-brain
+                // DNS) are all updated, same zone declared again overrides previous one
+                let zones: &mut Vec<&ZoneMapper> =
+                    zone_map.entry(zone.domain().to_lowercase()).or_default();
+                zones.retain(|previous| previous.zone() != zone.zone());
+                zones.push(zone);
             }
-            let mut zones = Vec::new();
+            // (domain, zone) => uuid, detect clients fighting over same record
+            let mut owners: HashMap<(String, String), &str> = HashMap::new();
             for element in value.clients() {
+                let mut zones = Vec::new();
+                // Every target should be resolved to its own zone (or zones sharing the domain)
                 for target in element.target() {
-                    let target_slice: Vec<_> = target.split('.').collect();
-                    for i in 0..target_slice.len() - 1 {
-                        let mid = target_slice[i..].join(".");
-                        if let Some(zone) = zone_map.get(mid.as_str()) {
-                            zones.push(ZoneMapper::new(target.to_string(), zone.to_string()));
-                            break;
+                    let target = target.to_lowercase();
+                    let matched = Self::resolve_zone(&target, &zone_map).ok_or_else(|| {
+                        ConfigError::Validation(format!(
+                            "Unable to find zone for target {:?} of client {}",
+                            target,
+                            element.uuid()
+                        ))
+                    })?;
+                    for zone in matched {
+                        if let Some(owner) =
+                            owners.insert((target.clone(), zone.zone().to_string()), element.uuid())
+                        {
+                            if owner != element.uuid() {
+                                let message = format!(
+                                    "Client {} and {} both target {:?}",
+                                    redact_uuid(owner),
+                                    redact_uuid(element.uuid()),
+                                    target
+                                );
+                                if value.strict_config() {
+                                    return Err(ConfigError::Validation(message));
+                                }
+                                warn!("{}, last pusher wins", message);
+                            }
+                        }
+                        // Record ID can't be shared by subdomains resolved to same zone
+                        if zone.record_id().is_some() && !target.eq_ignore_ascii_case(zone.domain())
+                        {
+                            return Err(ConfigError::Validation(format!(
+                                "record_id of zone {:?} can't be used by target {:?} of client {}",
+                                zone.domain(),
+                                target,
+                                element.uuid()
+                            )));
                         }
+                        zones.push(ZoneMapper::new(
+                            target.clone(),
+                            zone.zone().to_string(),
+                            zone.token_or(value.token()).to_string(),
+                            zone.comment().clone().or_else(|| value.comment().clone()),
+                            zone.mx_priority(),
+                            zone.record_id().map(|id| id.to_string()),
+                        ));
                     }
                 }
                 if zones.is_empty() {
-                    return Err(anyhow!("Zone is empty"));
+                    return Err(ConfigError::Validation("Zone is empty".to_string()));
                 }
-                m.insert(element.uuid().to_string(), zones.clone());
-                zones.clear();
+                let allowed_cidrs = element
+                    .allowed_cidrs()
+                    .iter()
+                    .map(|cidr| {
+                        cidr.parse::<IpNet>().map_err(|e| {
+                            ConfigError::Validation(format!("Parse CIDR {:?} error: {:?}", cidr, e))
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                let record_types = element
+                    .record_types()
+                    .iter()
+                    .map(|t| {
+                        let t = t.to_uppercase();
+                        if RECORD_TYPES.contains(&t.as_str()) {
+                            Ok(t)
+                        } else {
+                            Err(ConfigError::Validation(format!(
+                                "Unsupported record type {:?}, should be A or AAAA",
+                                t
+                            )))
+                        }
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                m.insert(
+                    element.uuid().to_string(),
+                    MappedClient {
+                        zones,
+                        allowed_cidrs,
+                        record_types,
+                        column: element.column().filter(|columns| !columns.is_empty()),
+                        hmac_key: element
+                            .hmac_key()
+                            .as_ref()
+                            .map(|key| hmac::Key::new(hmac::HMAC_SHA256, key.as_bytes())),
+                        providers: element.providers(),
+                    },
+                );
             }
             Ok(Self {
                 mapper: m,
                 relay: Default::default(),
                 client,
                 column: ip_column,
+                column_ipv6: ipv6_column,
+                redact_headers,
+                batch_concurrency,
+                api_base: api_prefix(value.api_base()),
+                create_missing_records: value.create_missing_records(),
+                default_ttl: value.default_ttl(),
+                default_proxied: value.default_proxied(),
+                use_peer_addr,
+                expose_api_error: value.expose_api_error(),
+                opaque_unknown_client: value.opaque_unknown_client(),
+                identifier_mode,
+                allow_private_ips: value.allow_private_ips(),
+                allow_lookup: value.allow_lookup(),
+                admin_token: value.admin_token().clone(),
+                cf_permits: Arc::new(Semaphore::new(value.max_concurrent_cf_requests())),
+                request_timeout,
+                update_log_level: value.update_log_level(),
+                unchanged_log_level: value.unchanged_log_level(),
+                min_update_interval: value.min_update_interval(),
+                ownership_check: value.ownership_check().clone(),
+                managed_name_prefix: value.managed_name_prefix().map(str::to_lowercase),
+                update_history: Default::default(),
+                last_pushed: Arc::new(Mutex::new(load_state(value.state_file()))),
+                state_file: value.state_file().map(|path| path.to_string()),
+                config_hash,
+                relay_queue: Default::default(),
             })
         }
     }
 
     impl ApiRequest {
-        pub async fn process_relay(&self, uuid: &String, new_ip: String) -> Result<bool, ApiError> {
-            let data = PostData::new(new_ip);
-            let mut update = false;
-            for upstream in self.relay.target() {
-                if let Ok(status) = self
-                    .client
-                    .post(format!("{}{}", upstream, uuid))
-                    .json(&data)
-                    .send()
-                    .await
-                    .map(|ret| ret.status())
-                    .tap_err(|e| error!("{}", e))
-                {
-                    if status.is_success() {
-                        update = true;
-                        break;
-                    }
-                    error!("Post to {} unsuccessful: {:?}", upstream, status)
-                }
+        // Find zones of the longest configured domain which is suffix of target
+        fn resolve_zone<'a, 'b>(
+            target: &str,
+            zone_map: &'b HashMap<String, Vec<&'a ZoneMapper>>,
+        ) -> Option<&'b [&'a ZoneMapper]> {
+            let target_slice: Vec<_> = target.split('.').collect();
+            (0..target_slice.len() - 1)
+                .find_map(|i| zone_map.get(target_slice[i..].join(".").as_str()))
+                .map(|zones| zones.as_slice())
+        }
+
+        // Put failed forward into queue, return false if queue is disabled or full
+        fn enqueue_relay(&self, target: &str, ip: String, request_id: &str) -> bool {
+            if !self.relay.queue() {
+                return false;
+            }
+            let mut queue = self.relay_queue.lock().unwrap();
+            // Older forward of same target is stale, retrying it later would overwrite this IP
+            queue.retain(|item| item.target != target);
+            if queue.len() >= self.relay.queue_capacity() {
+                warn!(
+                    "Relay queue is full, drop forward of {} (request {})",
+                    target, request_id
+                );
+                return false;
             }
-            Ok(update)
+            let now = Instant::now();
+            queue.push_back(QueuedForward {
+                target: target.to_string(),
+                ip,
+                request_id: request_id.to_string(),
+                created: now,
+                next_retry: now + RELAY_RETRY_BASE_DELAY,
+                attempts: 0,
+            });
+            true
         }
 
-        pub async fn request(&self, uuid: &String, new_ip: String) -> Result<bool, ApiError> {
+        // Queued forward of target is superseded by successful forward
+        fn dequeue_relay(&self, target: &str) {
+            self.relay_queue
+                .lock()
+                .unwrap()
+                .retain(|item| item.target != target);
+        }
+
+        pub async fn request(
+            &self,
+            uuid: &String,
+            new_ip: String,
+            request_id: &str,
+            // Update even if record is same or in cooldown, ignored in relay mode
+            force: bool,
+        ) -> Result<UpdateResult, ApiError> {
             if self.relay.enabled() {
                 let uuid = self
                     .relay
                     .clients()
                     .get(uuid)
-                    .ok_or_else(ApiError::forbidden)?;
+                    .ok_or_else(|| self.unknown_client())?;
 
-                return self.process_relay(&uuid, new_ip).await;
+                return match self
+                    .relay_forwarder()
+                    .process_relay(uuid, new_ip.clone(), request_id)
+                    .await
+                {
+                    Ok(()) => {
+                        self.dequeue_relay(uuid);
+                        Ok(UpdateStatus::Updated.into())
+                    }
+                    // Rejection of upstream (e.g. unknown client) is not retried
+                    Err(e) if e.is_transient() && self.enqueue_relay(uuid, new_ip, request_id) => {
+                        Ok(UpdateStatus::Queued.into())
+                    }
+                    Err(e) => Err(e),
+                };
             }
 
-            let zones = self.mapper.get(uuid).ok_or_else(ApiError::forbidden)?;
+            let client = self.mapper.get(uuid).ok_or_else(|| self.unknown_client())?;
+            // Rejected at loading, checked again so no record outside prefix is ever touched
+            for zone in client.zones() {
+                self.check_managed(zone)?;
+            }
+
+            let ip = parse_ip(&new_ip).ok_or(ApiError::BadRequest)?;
+            if !self.allow_private_ips && !is_public_ip(&ip) {
+                warn!("{} pushed non-public address {}, rejected", uuid, ip);
+                return Err(ApiError::BadRequest);
+            }
+            let record_type = record_type(&ip);
+            if !client.accept_record_type(record_type) {
+                return Err(ApiError::BadRequest);
+            }
+            // Canonical form, e.g. `2001:0db8::0001` => `2001:db8::1`
+            let new_ip = ip.to_string();
+            self.last_pushed
+                .lock()
+                .unwrap()
+                .insert((uuid.clone(), record_type), new_ip.clone());
 
             let mut updated = false;
+            let mut first_error = None;
+            let mut zones = Vec::new();
 
-            for zone in zones {
-                if let Ok(mut record) =
-                    DNSRecord::fetch_dns_record(&self.client, zone.zone(), zone.domain())
-                        .await
-                        .tap_err(|e| error!("{}", e))
+            // Every (zone, provider) pair has its own result, partial success is reported as
+            // updated with error of failed pairs in zone results
+            for zone in client.zones() {
+                for provider in client.providers() {
+                    let _permit = self.acquire_cf_permit().await?;
+                    let (name, ret) = match provider {
+                        Provider::Cloudflare => {
+                            let provider = self.cloudflare();
+                            let ret = match self.is_owner(zone).await {
+                                Ok(true) => {
+                                    self.update_zone(
+                                        &provider,
+                                        zone,
+                                        record_type,
+                                        &ip,
+                                        &new_ip,
+                                        force,
+                                    )
+                                    .await
+                                }
+                                ret => ret.map(|_| false),
+                            };
+                            if let (Ok(true), Some(priority)) = (&ret, zone.mx_priority()) {
+                                if let Err(e) = self.assert_mx_record(zone, priority).await {
+                                    error!(
+                                        "Processing MX: {} {} {}",
+                                        zone.domain(),
+                                        zone.zone(),
+                                        e
+                                    );
+                                }
+                            }
+                            (provider.name(), ret)
+                        }
+                    };
+                    match ret {
+                        Ok(zone_updated) => {
+                            if zone_updated && !updated {
+                                updated = true;
+                                log!(self.update_log_level, "Update {} IP to {}", uuid, new_ip);
+                            }
+                            zones.push(ZoneResult::new(zone.domain(), name, zone_updated, None));
+                        }
+                        Err(e) => {
+                            error!(
+                                "Processing: {} {} ({}) {}{}",
+                                zone.domain(),
+                                zone.zone(),
+                                name,
+                                e,
+                                if zone.domain().starts_with("*.") {
+                                    " (wildcard record: proxied wildcard may be restricted by cloudflare plan)"
+                                } else {
+                                    ""
+                                }
+                            );
+                            zones.push(ZoneResult::new(
+                                zone.domain(),
+                                name,
+                                false,
+                                Some(if self.expose_api_error {
+                                    e.message()
+                                } else {
+                                    e.kind().to_string()
+                                }),
+                            ));
+                            first_error.get_or_insert(e);
+                        }
+                    }
+                }
+            }
+
+            // Only cloudflare error is exposed, since other error may contain local information
+            if !updated && self.expose_api_error {
+                if let Some(e @ ApiError::Cloudflare { .. }) = first_error {
+                    return Err(e);
+                }
+            }
+
+            Ok(UpdateResult {
+                status: if updated {
+                    UpdateStatus::Updated
+                } else {
+                    UpdateStatus::Unchanged
+                },
+                zones,
+            })
+        }
+
+        fn cloudflare(&self) -> Cloudflare<'_> {
+            Cloudflare {
+                client: &self.client,
+                api_base: &self.api_base,
+                create_missing_records: self.create_missing_records,
+                default_ttl: self.default_ttl,
+                default_proxied: self.default_proxied,
+            }
+        }
+
+        // Return true if record is updated or created
+        async fn update_zone<P: DnsProvider + Sync>(
+            &self,
+            provider: &P,
+            zone: &ZoneMapper,
+            record_type: &str,
+            ip: &IpAddr,
+            new_ip: &String,
+            force: bool,
+        ) -> Result<bool, ApiError> {
+            let record = provider.fetch(zone, record_type).await?;
+            let previous = record.as_ref().map(|record| P::content(record).to_string());
+            if let Some(previous) = previous.as_ref().filter(|_| !force) {
+                // Compare parsed address, textual form of record may differ
+                if previous
+                    .parse::<IpAddr>()
+                    .is_ok_and(|content| content == *ip)
                 {
-                    if !record.content().eq(&new_ip) {
-                        record.set_content(new_ip.clone());
+                    return Ok(false);
+                }
+                if self.in_cooldown(zone, new_ip) {
+                    debug!(
+                        "Skip update {} to {}, last update is within cooldown",
+                        zone.domain(),
+                        new_ip
+                    );
+                    return Ok(false);
+                }
+            }
+            if !provider.update(zone, record_type, record, new_ip).await? {
+                // Record is changed to same value between fetch and update
+                debug!(
+                    "Redundant update of {} to {}, record is not modified",
+                    zone.domain(),
+                    new_ip
+                );
+                return Ok(false);
+            }
+            if let Some(previous) = previous {
+                self.record_update(zone, previous, new_ip.clone());
+            }
+            Ok(true)
+        }
+
+        // Refuse record whose name doesn't start with `managed_name_prefix`
+        fn check_managed(&self, zone: &ZoneMapper) -> Result<(), ApiError> {
+            match self.managed_name_prefix {
+                Some(ref prefix) if !zone.domain().to_lowercase().starts_with(prefix) => {
+                    warn!(
+                        "Refuse to update {}, name doesn't start with managed prefix {:?}",
+                        zone.domain(),
+                        prefix
+                    );
+                    Err(ApiError::Forbidden)
+                }
+                _ => Ok(()),
+            }
+        }
+
+        // True if ownership check is disabled or companion TXT record holds owner ID of this
+        // instance, missing TXT record is treated as owned by other instance
+        async fn is_owner(&self, zone: &ZoneMapper) -> Result<bool, ApiError> {
+            let Some(ref check) = self.ownership_check else {
+                return Ok(true);
+            };
+            let name = check.name(zone.domain());
+            let record = DNSRecord::fetch_dns_record(
+                &self.client,
+                &self.api_base,
+                zone.token(),
+                zone.zone(),
+                &name,
+                "TXT",
+            )
+            .await?;
+            // TXT content may be returned quoted
+            let owner = record
+                .as_ref()
+                .map(|record| record.content().trim_matches('"'));
+            if owner == Some(check.owner()) {
+                return Ok(true);
+            }
+            warn!(
+                "Skip update {}, owner in TXT record {} is {:?} instead of {:?}",
+                zone.domain(),
+                name,
+                owner,
+                check.owner()
+            );
+            Ok(false)
+        }
+
+        // MX record of target points to target itself, only created or corrected if differ
+        async fn assert_mx_record(&self, zone: &ZoneMapper, priority: u16) -> Result<(), ApiError> {
+            match DNSRecord::fetch_dns_record(
+                &self.client,
+                &self.api_base,
+                zone.token(),
+                zone.zone(),
+                zone.domain(),
+                "MX",
+            )
+            .await?
+            {
+                Some(record)
+                    if record.content().eq_ignore_ascii_case(zone.domain())
+                        && record.priority() == Some(priority) =>
+                {
+                    Ok(())
+                }
+                Some(mut record) => {
+                    info!("Correct MX record of {}", zone.domain());
+                    record.set_content(zone.domain().to_string());
+                    record.set_priority(priority);
+                    record
+                        .update_ns_record(&self.client, &self.api_base, zone.token())
+                        .await
+                        .map(|_| ())
+                }
+                None => {
+                    info!("MX record {} not found, create new record", zone.domain());
+                    DNSRecord::create_ns_record(
+                        &self.client,
+                        &self.api_base,
+                        zone.token(),
+                        zone.zone(),
+                        // MX record can't be proxied
+                        &PutDNSRecord::new(
+                            "MX".to_string(),
+                            zone.domain().to_string(),
+                            zone.domain().to_string(),
+                            false,
+                            self.default_ttl,
+                            zone.comment().clone(),
+                        )
+                        .set_priority(priority),
+                    )
+                    .await
+                }
+            }
+        }
+
+        // Re-assert last pushed IP of every client, called by background task
+        pub async fn reconcile(&self) {
+            if self.is_relay() {
+                return;
+            }
+            let last_pushed = self.last_pushed.lock().unwrap().clone();
+            for ((uuid, record_type), ip) in last_pushed {
+                // Client may be removed by reload
+                if !self.mapper.contains_key(&uuid) {
+                    continue;
+                }
+                match self.request(&uuid, ip.clone(), "reconcile", false).await {
+                    Ok(result) if result.status() == UpdateStatus::Updated => {
+                        warn!(
+                            "Drift of {} {} record corrected to {}",
+                            uuid, record_type, ip
+                        );
+                    }
+                    Ok(_) => {}
+                    Err(e) => error!("Reconcile {} {} record error: {}", uuid, record_type, e),
+                }
+            }
+        }
+
+        pub fn has_hmac_key(&self, uuid: &str) -> bool {
+            self.mapper
+                .get(uuid)
+                .is_some_and(|client| client.hmac_key.is_some())
+        }
+
+        // Client without key is always passed
+        pub fn verify_signature(&self, uuid: &str, message: &[u8], signature: &[u8]) -> bool {
+            match self
+                .mapper
+                .get(uuid)
+                .and_then(|client| client.hmac_key.as_ref())
+            {
+                Some(key) => hmac::verify(key, message, signature).is_ok(),
+                None => true,
+            }
+        }
+
+        // Check submitted IP is in client allowed CIDRs, unknown client will be rejected by request
+        pub fn is_allowed(&self, uuid: &str, ip: &str) -> bool {
+            self.mapper
+                .get(uuid)
+                .map(|client| client.is_allowed(ip))
+                .unwrap_or(true)
+        }
+
+        pub fn accept_ipv6(&self, uuid: &str) -> bool {
+            self.mapper
+                .get(uuid)
+                .map(|client| client.accept_record_type("AAAA"))
+                .unwrap_or(true)
+        }
+
+        // Call cloudflare token verify endpoint, return error if token is not active
+        pub async fn verify_token(&self) -> anyhow::Result<()> {
+            let mut tokens = self
+                .mapper
+                .values()
+                .flat_map(|client| client.zones().iter().map(|zone| zone.token()))
+                .collect::<Vec<_>>();
+            tokens.sort();
+            tokens.dedup();
+            for token in tokens {
+                self.verify_single_token(token).await?;
+            }
+            Ok(())
+        }
+
+        // Send GET to every relay target, return number of targets responded
+        pub async fn check_relay_targets(&self) -> usize {
+            let mut reachable = 0;
+            for target in self.relay.target() {
+                match self.client.get(target).send().await {
+                    Ok(resp) => {
+                        info!(
+                            "Relay target {} is reachable: {}",
+                            redact_url(target),
+                            resp.status()
+                        );
+                        reachable += 1;
+                    }
+                    Err(e) => warn!("Relay target {} is unreachable: {}", redact_url(target), e),
+                }
+            }
+            reachable
+        }
+
+        async fn verify_single_token(&self, token: &str) -> anyhow::Result<()> {
+            let resp = send_request(
+                self.client
+                    .get(format!("{}/user/tokens/verify", self.api_base))
+                    .bearer_auth(token),
+            )
+            .await
+            .map_err(|e| anyhow!("Got error while verify token: {:?}", e))?;
+            let resp = CloudFlareResult::from_response(resp)
+                .await
+                .map_err(|e| anyhow!("Verify token failed: {}", e))?;
+            let status = resp
+                .result()
+                .get("status")
+                .and_then(|status| status.as_str().map(|s| s.to_string()))
+                .unwrap_or_default();
+            if status.ne("active") {
+                return Err(anyhow!("Cloudflare token is not active: {:?}", status));
+            }
+            info!("Cloudflare token is active");
+            Ok(())
+        }
+
+        // Delete all mapped records of client, return NotFound if no record exists
+        pub async fn delete(&self, uuid: &String) -> Result<(), ApiError> {
+            if self.relay.enabled() {
+                return Err(ApiError::forbidden());
+            }
+
+            let client = self.mapper.get(uuid).ok_or_else(|| self.unknown_client())?;
+
+            let mut found = false;
+            for zone in client.zones() {
+                let _permit = self.acquire_cf_permit().await?;
+                for record_type in RECORD_TYPES {
+                    if !client.accept_record_type(record_type) {
+                        continue;
+                    }
+                    let record = DNSRecord::fetch_dns_record(
+                        &self.client,
+                        &self.api_base,
+                        zone.token(),
+                        zone.zone(),
+                        zone.domain(),
+                        record_type,
+                    )
+                    .await
+                    .map_err(|e| self.generic_api_error(e))?;
+                    if let Some(record) = record {
+                        found = true;
                         record
-                            .update_ns_record(&self.client)
+                            .delete_ns_record(&self.client, &self.api_base, zone.token())
                             .await
-                            .map(|ret| {
-                                if ret && !updated {
-                                    updated = true;
-                                    info!("Update {} IP to {}", uuid, new_ip);
-                                }
-                                ret
-                            })
-                            .tap_err(|e| {
-                                error!("Processing: {} {} {}", zone.domain(), zone.zone(), e)
-                            })
-                            .ok();
+                            .map_err(|e| self.generic_api_error(e))?;
+                        info!("Delete {} {} record {}", uuid, record_type, zone.domain());
                     }
-                };
+                }
             }
 
-            Ok(updated)
+            if !found {
+                return Err(ApiError::NotFound);
+            }
+            Ok(())
+        }
+
+        // Cloudflare error may contain zone information, only exposed if `expose_api_error` is set
+        fn generic_api_error(&self, e: ApiError) -> ApiError {
+            match e {
+                ApiError::Cloudflare { .. } if !self.expose_api_error => {
+                    ApiError::Other(anyhow!("{}", e))
+                }
+                e => e,
+            }
+        }
+
+        // Wait for free slot, give up if cloudflare requests are piled up
+        async fn acquire_cf_permit(&self) -> Result<SemaphorePermit<'_>, ApiError> {
+            tokio::time::timeout(CF_PERMIT_TIMEOUT, self.cf_permits.acquire())
+                .await
+                .map_err(|_| {
+                    warn!("Timeout while waiting for cloudflare request slot");
+                    ApiError::ServiceUnavailable
+                })?
+                .map_err(|e| anyhow!("Semaphore closed: {:?}", e).into())
+        }
+
+        // Fetch current records of client without updating
+        pub async fn lookup(&self, uuid: &String) -> Result<Vec<LookupRecord>, ApiError> {
+            if self.relay.enabled() {
+                return Err(ApiError::forbidden());
+            }
+
+            let client = self.mapper.get(uuid).ok_or_else(|| self.unknown_client())?;
+
+            let mut records = Vec::new();
+            for zone in client.zones() {
+                let _permit = self.acquire_cf_permit().await?;
+                for record_type in RECORD_TYPES {
+                    if !client.accept_record_type(record_type) {
+                        continue;
+                    }
+                    if let Some(record) = DNSRecord::fetch_dns_record(
+                        &self.client,
+                        &self.api_base,
+                        zone.token(),
+                        zone.zone(),
+                        zone.domain(),
+                        record_type,
+                    )
+                    .await
+                    .map_err(|e| self.generic_api_error(e))?
+                    {
+                        records.push(record.into());
+                    }
+                }
+            }
+            Ok(records)
+        }
+
+        // Flapping between last two values within interval is refused
+        fn in_cooldown(&self, zone: &ZoneMapper, new_ip: &String) -> bool {
+            let Some(interval) = self.min_update_interval else {
+                return false;
+            };
+            self.update_history
+                .lock()
+                .unwrap()
+                .get(&(zone.zone().to_string(), zone.domain().to_string()))
+                .map(|history| {
+                    history.last_update.elapsed() < interval && history.recent.contains(new_ip)
+                })
+                .unwrap_or_default()
+        }
+
+        fn record_update(&self, zone: &ZoneMapper, previous: String, current: String) {
+            if self.min_update_interval.is_none() {
+                return;
+            }
+            self.update_history.lock().unwrap().insert(
+                (zone.zone().to_string(), zone.domain().to_string()),
+                UpdateHistory {
+                    last_update: Instant::now(),
+                    recent: [previous, current],
+                },
+            );
+        }
+
+        // Save last pushed IP of every client to state file, called on shutdown
+        pub async fn save_state(&self) {
+            let Some(ref path) = self.state_file else {
+                return;
+            };
+            let mut state: BTreeMap<&str, BTreeMap<&str, &str>> = BTreeMap::new();
+            let last_pushed = self.last_pushed.lock().unwrap().clone();
+            for ((uuid, record_type), ip) in &last_pushed {
+                state.entry(uuid).or_default().insert(record_type, ip);
+            }
+            let content = match serde_json::to_string_pretty(&state) {
+                Ok(content) => content,
+                Err(e) => {
+                    error!("Unable serialize state: {}", e);
+                    return;
+                }
+            };
+            // Write to temporary file then rename, interrupted write can't truncate previous state
+            let tmp_path = format!("{}.tmp", path);
+            let ret = async {
+                let mut file = tokio::fs::File::create(&tmp_path).await?;
+                file.write_all(content.as_bytes()).await?;
+                file.sync_all().await?;
+                tokio::fs::rename(&tmp_path, path).await
+            }
+            .await;
+            if let Err(e) = ret {
+                error!("Unable write state file {:?}: {}", path, e);
+            } else {
+                debug!(
+                    "Saved {} entries to state file {:?}",
+                    last_pushed.len(),
+                    path
+                );
+            }
+        }
+
+        pub fn relay_forwarder(&self) -> RelayForwarder {
+            RelayForwarder {
+                relay: self.relay.clone(),
+                client: self.client.clone(),
+                relay_queue: self.relay_queue.clone(),
+            }
+        }
+
+        pub fn relay_queue_len(&self) -> usize {
+            self.relay_queue.lock().unwrap().len()
+        }
+
+        // Keep runtime state (relay queue, update history) across configure reload
+        pub fn inherit_state(&mut self, old: &ApiRequest) {
+            self.update_history = old.update_history.clone();
+            self.last_pushed = old.last_pushed.clone();
+            self.relay_queue = old.relay_queue.clone();
         }
 
         pub fn is_relay(&self) -> bool {
             self.relay.enabled()
         }
 
+        // Identifier in route path, checked before looking up client
+        pub fn is_valid_id(&self, id: &str) -> bool {
+            self.identifier_mode.is_valid(id)
+        }
+
+        pub fn relay_missing_header(&self) -> MissingHeader {
+            self.relay.missing_header()
+        }
+
         pub fn info(&self) -> String {
             format!(
-                "relay mode: {}, {}",
+                "sha256: {}, relay mode: {}, {}",
+                self.config_hash,
                 self.is_relay(),
                 if self.is_relay() {
                     format!(
@@ -348,23 +1982,725 @@ mod api {
                 }
             )
         }
-        fn set_column(mut self, column: String) -> Self {
+        // Global settings of effective configure, without clients
+        pub fn effective_settings(&self) -> serde_json::Value {
+            json!({
+                "relay": self.is_relay(),
+                "relay_targets": self.relay.target().iter().map(|target| redact_url(target)).collect::<Vec<_>>(),
+                "column": self.column,
+                "column_ipv6": self.column_ipv6,
+                "use_peer_addr": self.use_peer_addr,
+            })
+        }
+
+        // Identifiers of clients, used to walk clients one by one
+        pub fn client_ids(&self) -> Vec<String> {
+            if self.is_relay() {
+                self.relay.clients().keys().cloned().collect()
+            } else {
+                self.mapper.keys().cloned().collect()
+            }
+        }
+
+        pub fn effective_client(&self, uuid: &str) -> Option<serde_json::Value> {
+            if self.is_relay() {
+                return self
+                    .relay
+                    .clients()
+                    .get(uuid)
+                    .map(|target| json!({ "target": target }));
+            }
+            self.mapper.get(uuid).map(|client| {
+                json!({
+                    "zones": client.zones().iter().map(|zone| json!({
+                        "domain": zone.domain(),
+                        "zone": zone.zone(),
+                    })).collect::<Vec<_>>(),
+                    "allowed_cidrs": client.allowed_cidrs.iter().map(|net| net.to_string()).collect::<Vec<_>>(),
+                    "record_types": client.record_types,
+                    "column": client.column.as_ref().unwrap_or(&self.column),
+                })
+            })
+        }
+
+        pub fn effective_config(&self) -> serde_json::Value {
+            let mut config = self.effective_settings();
+            config["clients"] = self
+                .client_ids()
+                .into_iter()
+                .filter_map(|uuid| {
+                    let client = self.effective_client(&uuid)?;
+                    Some((uuid, client))
+                })
+                .collect::<serde_json::Map<_, _>>()
+                .into();
+            config
+        }
+
+        // Compare in constant time, avoid leaking token by response timing
+        pub fn check_admin_token(&self, token: &str) -> bool {
+            self.admin_token.as_deref().is_some_and(|admin_token| {
+                !admin_token.is_empty()
+                    && ring::constant_time::verify_slices_are_equal(
+                        admin_token.as_bytes(),
+                        token.as_bytes(),
+                    )
+                    .is_ok()
+            })
+        }
+
+        fn set_identifier_mode(mut self, identifier_mode: IdentifierMode) -> Self {
+            self.identifier_mode = identifier_mode;
+            self
+        }
+
+        fn set_config_hash(mut self, config_hash: String) -> Self {
+            self.config_hash = config_hash;
+            self
+        }
+
+        fn set_log_level(mut self, update: Level, unchanged: Level) -> Self {
+            self.update_log_level = update;
+            self.unchanged_log_level = unchanged;
+            self
+        }
+
+        pub fn update_log_level(&self) -> Level {
+            self.update_log_level
+        }
+
+        pub fn unchanged_log_level(&self) -> Level {
+            self.unchanged_log_level
+        }
+
+        fn set_request_timeout(mut self, request_timeout: Duration) -> Self {
+            self.request_timeout = request_timeout;
+            self
+        }
+
+        pub fn request_timeout(&self) -> Duration {
+            self.request_timeout
+        }
+
+        fn set_admin_token(mut self, admin_token: Option<String>) -> Self {
+            self.admin_token = admin_token;
+            self
+        }
+
+        fn set_column(mut self, column: Vec<String>, column_ipv6: Vec<String>) -> Self {
             self.column = column;
+            self.column_ipv6 = column_ipv6;
+            self
+        }
+        pub fn column_ipv6(&self) -> &[String] {
+            &self.column_ipv6
+        }
+        fn set_redact_headers(mut self, redact_headers: Vec<String>) -> Self {
+            self.redact_headers = redact_headers;
+            self
+        }
+        fn set_batch_concurrency(mut self, batch_concurrency: usize) -> Self {
+            self.batch_concurrency = batch_concurrency;
+            self
+        }
+        pub fn batch_concurrency(&self) -> usize {
+            self.batch_concurrency
+        }
+        pub fn is_redacted_header(&self, name: &str) -> bool {
+            self.redact_headers
+                .iter()
+                .any(|header| header.eq_ignore_ascii_case(name))
+        }
+        // Client column take precedence over global column
+        pub fn column(&self, uuid: &str) -> &[String] {
+            self.mapper
+                .get(uuid)
+                .and_then(|client| client.column.as_deref())
+                .unwrap_or(&self.column)
+        }
+        fn set_use_peer_addr(mut self, use_peer_addr: bool) -> Self {
+            self.use_peer_addr = use_peer_addr;
+            self
+        }
+        pub fn use_peer_addr(&self) -> bool {
+            self.use_peer_addr
+        }
+        pub fn allow_lookup(&self) -> bool {
+            self.allow_lookup
+        }
+        fn set_opaque_unknown_client(mut self, opaque_unknown_client: bool) -> Self {
+            self.opaque_unknown_client = opaque_unknown_client;
             self
         }
-        pub fn column(&self) -> &str {
-            &self.column
+        // Unknown client is 404 by default, or 403 to make it indistinguishable from auth failure
+        fn unknown_client(&self) -> ApiError {
+            if self.opaque_unknown_client {
+                ApiError::forbidden()
+            } else {
+                ApiError::NotFound
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use mockito::{Matcher, Server, ServerGuard};
+
+        const UUID: &str = "db5770ec-750b-4dd9-9fe2-2bf374b0ab50";
+
+        async fn from_toml(content: &str) -> ApiRequest {
+            ApiRequest::try_from(Config::try_from_toml(content).await.unwrap()).unwrap()
+        }
+
+        // (domain, zone) of resolved zones of client
+        fn resolved_zones(request: &ApiRequest, uuid: &str) -> Vec<(String, String)> {
+            let mut zones = request.mapper[uuid]
+                .zones()
+                .iter()
+                .map(|zone| (zone.domain().to_string(), zone.zone().to_string()))
+                .collect::<Vec<_>>();
+            zones.sort();
+            zones
+        }
+
+        // Client updating `target` in zone `z` of `example.com`, cloudflare api points to mock
+        async fn build_request(server: &ServerGuard, target: &str, extra: &str) -> ApiRequest {
+            from_toml(&format!(
+                r#"
+token = "CF_TOKEN"
+api_base = "{}"
+{}
+
+[server]
+host = "127.0.0.1"
+port = 21336
+
+[[client]]
+uuid = "{}"
+target = ["{}"]
+
+[[zones]]
+domain = "example.com"
+zone = "z"
+"#,
+                server.url(),
+                extra,
+                UUID,
+                target
+            ))
+            .await
+        }
+
+        fn records_body(records: serde_json::Value) -> String {
+            json!({
+                "success": true,
+                "errors": [],
+                "result": records,
+                "result_info": { "total_pages": 1 },
+            })
+            .to_string()
+        }
+
+        fn a_record(content: &str) -> serde_json::Value {
+            json!({
+                "id": "r1",
+                "type": "A",
+                "zone_id": "z",
+                "name": "test.example.com",
+                "content": content,
+                "proxied": false,
+                "ttl": 1,
+                "modified_on": "2021-01-01T00:00:00Z",
+            })
+        }
+
+        #[test]
+        fn test_resolve_zone() {
+            let zones = [
+                ("example.com", "z1"),
+                ("sub.example.com", "z2"),
+                ("myexample.com", "z3"),
+            ]
+            .map(|(domain, zone)| {
+                ZoneMapper::new(
+                    domain.to_string(),
+                    zone.to_string(),
+                    String::new(),
+                    None,
+                    None,
+                    None,
+                )
+            });
+            let zone_map = zones
+                .iter()
+                .map(|zone| (zone.domain().to_string(), vec![zone]))
+                .collect::<HashMap<_, _>>();
+            let resolve = |target| {
+                ApiRequest::resolve_zone(target, &zone_map)
+                    .map(|zones| zones.iter().map(|zone| zone.zone()).collect::<Vec<_>>())
+            };
+
+            assert_eq!(resolve("a.example.com"), Some(vec!["z1"]));
+            assert_eq!(resolve("example.com"), Some(vec!["z1"]));
+            // Longest matching domain wins
+            assert_eq!(resolve("a.sub.example.com"), Some(vec!["z2"]));
+            // Only whole labels are matched
+            assert_eq!(resolve("a.myexample.com"), Some(vec!["z3"]));
+            assert_eq!(resolve("a.example.org"), None);
+            assert_eq!(resolve("com"), None);
+        }
+
+        #[tokio::test]
+        async fn test_targets_in_different_zones() {
+            let request = from_toml(&format!(
+                r#"
+token = "CF_TOKEN"
+
+[server]
+host = "127.0.0.1"
+port = 21336
+
+[[client]]
+uuid = "{}"
+target = ["a.example.com", "b.example.org", "c.sub.net"]
+
+[[zones]]
+domain = "example.com"
+zone = "z1"
+
+[[zones]]
+domain = "example.org"
+zone = "z2"
+
+[[zones]]
+domain = "sub.net"
+zone = "z3"
+
+[[zones]]
+domain = "net"
+zone = "z4"
+"#,
+                UUID
+            ))
+            .await;
+            assert_eq!(
+                resolved_zones(&request, UUID),
+                [
+                    ("a.example.com", "z1"),
+                    ("b.example.org", "z2"),
+                    ("c.sub.net", "z3"),
+                ]
+                .map(|(domain, zone)| (domain.to_string(), zone.to_string()))
+            );
+        }
+
+        #[tokio::test]
+        async fn test_target_in_zones_sharing_domain() {
+            let request = from_toml(&format!(
+                r#"
+token = "CF_TOKEN"
+
+[server]
+host = "127.0.0.1"
+port = 21336
+
+[[client]]
+uuid = "{}"
+target = ["home.example.com"]
+
+[[zones]]
+domain = "example.com"
+zone = "z1"
+
+[[zones]]
+domain = "Example.com"
+zone = "z2"
+
+[[zones]]
+domain = "example.com"
+zone = "z1"
+comment = "declared again"
+"#,
+                UUID
+            ))
+            .await;
+            // Same zone declared again is not updated twice
+            assert_eq!(
+                resolved_zones(&request, UUID),
+                [("home.example.com", "z1"), ("home.example.com", "z2")]
+                    .map(|(domain, zone)| (domain.to_string(), zone.to_string()))
+            );
+        }
+
+        #[test]
+        fn test_parse_ipv4_mapped() {
+            let ip = parse_ip("::ffff:203.0.113.5").unwrap();
+            assert_eq!(ip, "203.0.113.5".parse::<IpAddr>().unwrap());
+            assert_eq!(ip.to_string(), "203.0.113.5");
+            assert_eq!(record_type(&ip), "A");
+            // Other IPv6 address is kept
+            let ip = parse_ip("2606:4700::1").unwrap();
+            assert_eq!(record_type(&ip), "AAAA");
+            assert!(parse_ip("::ffff:203.0.113").is_none());
+        }
+
+        #[test]
+        fn test_put_record_priority() {
+            let record = |priority: Option<u16>| {
+                let record = PutDNSRecord::new(
+                    "MX".to_string(),
+                    "test.example.com".to_string(),
+                    "test.example.com".to_string(),
+                    false,
+                    1,
+                    None,
+                );
+                serde_json::to_value(match priority {
+                    Some(priority) => record.set_priority(priority),
+                    None => record,
+                })
+                .unwrap()
+            };
+            assert_eq!(record(Some(10))["priority"], 10);
+            assert_eq!(record(Some(10))["type"], "MX");
+            // Omitted for A and AAAA record
+            assert!(record(None).get("priority").is_none());
+        }
+
+        #[tokio::test]
+        async fn test_fetch_wildcard_name() {
+            let mut server = Server::new_async().await;
+            // Raw query string, `*` is sent literally
+            let fetch = server
+                .mock("GET", "/zones/z/dns_records")
+                .match_query(Matcher::Regex(
+                    r"(^|&)name=\*\.home\.example\.com(&|$)".into(),
+                ))
+                .with_header("content-type", "application/json")
+                .with_body(records_body(json!([])))
+                .create_async()
+                .await;
+
+            let record = DNSRecord::fetch_dns_record(
+                &reqwest::Client::new(),
+                &server.url(),
+                "CF_TOKEN",
+                "z",
+                "*.home.example.com",
+                "A",
+            )
+            .await
+            .unwrap();
+            assert!(record.is_none());
+            fetch.assert_async().await;
+        }
+
+        #[tokio::test]
+        async fn test_fetch_multiple_pages() {
+            let mut server = Server::new_async().await;
+            let mut pages = Vec::new();
+            for (page, id) in [(1, "r1"), (2, "r2")] {
+                let mut record = a_record("1.1.1.1");
+                record["id"] = id.into();
+                pages.push(
+                    server
+                        .mock("GET", "/zones/z/dns_records")
+                        .match_query(Matcher::AllOf(vec![
+                            Matcher::UrlEncoded("page".into(), page.to_string()),
+                            Matcher::UrlEncoded(
+                                "per_page".into(),
+                                DNS_RECORDS_PER_PAGE.to_string(),
+                            ),
+                        ]))
+                        .with_header("content-type", "application/json")
+                        .with_body(
+                            json!({
+                                "success": true,
+                                "errors": [],
+                                "result": [record],
+                                "result_info": { "page": page, "total_pages": 2 },
+                            })
+                            .to_string(),
+                        )
+                        .create_async()
+                        .await,
+                );
+            }
+
+            // Last record of last page is used
+            let record = DNSRecord::fetch_dns_record(
+                &reqwest::Client::new(),
+                &server.url(),
+                "CF_TOKEN",
+                "z",
+                "test.example.com",
+                "A",
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            assert_eq!(record.id(), "r2");
+            for page in pages {
+                page.assert_async().await;
+            }
+        }
+
+        #[tokio::test]
+        async fn test_save_state() {
+            let server = Server::new_async().await;
+            let dir = std::env::temp_dir().join(format!("waffle-state-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("state.json");
+            std::fs::write(&path, "{}").unwrap();
+            let extra = format!("state_file = {:?}", path.to_str().unwrap());
+
+            let request = build_request(&server, "test.example.com", &extra).await;
+            request
+                .last_pushed
+                .lock()
+                .unwrap()
+                .insert((UUID.to_string(), "A"), "1.1.1.1".to_string());
+            request.save_state().await;
+            // Previous state is replaced, temporary file is renamed away
+            assert!(!dir.join("state.json.tmp").exists());
+            let reloaded = load_state(path.to_str());
+            std::fs::remove_dir_all(&dir).unwrap();
+            assert_eq!(
+                reloaded.get(&(UUID.to_string(), "A")).map(String::as_str),
+                Some("1.1.1.1")
+            );
+        }
+
+        #[tokio::test]
+        async fn test_lookup_hide_identifiers() {
+            let mut server = Server::new_async().await;
+            let mut mocks = Vec::new();
+            for (record_type, records) in [("A", json!([a_record("1.1.1.1")])), ("AAAA", json!([]))]
+            {
+                mocks.push(
+                    server
+                        .mock("GET", "/zones/z/dns_records")
+                        .match_query(Matcher::UrlEncoded("type".into(), record_type.into()))
+                        .with_header("content-type", "application/json")
+                        .with_body(records_body(records))
+                        .create_async()
+                        .await,
+                );
+            }
+
+            let request = build_request(&server, "test.example.com", "").await;
+            let records =
+                serde_json::to_value(request.lookup(&UUID.to_string()).await.unwrap()).unwrap();
+            assert!(records[0].get("zone_id").is_none());
+            assert!(records[0].get("id").is_none());
+            assert_eq!(
+                records,
+                json!([{
+                    "name": "test.example.com",
+                    "type": "A",
+                    "content": "1.1.1.1",
+                    "ttl": 1,
+                    "proxied": false,
+                }])
+            );
+            for mock in mocks {
+                mock.assert_async().await;
+            }
+        }
+
+        #[tokio::test]
+        async fn test_request_update() {
+            let mut server = Server::new_async().await;
+            let fetch = server
+                .mock("GET", "/zones/z/dns_records")
+                .match_header("authorization", "Bearer CF_TOKEN")
+                .match_query(Matcher::AllOf(vec![
+                    Matcher::UrlEncoded("type".into(), "A".into()),
+                    Matcher::UrlEncoded("name".into(), "test.example.com".into()),
+                    Matcher::UrlEncoded("page".into(), "1".into()),
+                ]))
+                .with_header("content-type", "application/json")
+                .with_body(records_body(json!([a_record("1.1.1.1")])))
+                .create_async()
+                .await;
+            let update = server
+                .mock("PUT", "/zones/z/dns_records/r1")
+                .match_body(Matcher::Json(json!({
+                    "type": "A",
+                    "name": "test.example.com",
+                    "content": "8.8.8.8",
+                    "proxied": false,
+                    "ttl": 1,
+                })))
+                .with_header("content-type", "application/json")
+                .with_body(
+                    json!({
+                        "success": true,
+                        "errors": [],
+                        "result": { "modified_on": "2021-01-02T00:00:00Z" },
+                    })
+                    .to_string(),
+                )
+                .create_async()
+                .await;
+
+            let request = build_request(&server, "test.example.com", "").await;
+            let result = request
+                .request(&UUID.to_string(), "8.8.8.8".to_string(), "test", false)
+                .await
+                .unwrap();
+            assert_eq!(result.status(), UpdateStatus::Updated);
+            fetch.assert_async().await;
+            update.assert_async().await;
+        }
+
+        #[tokio::test]
+        async fn test_request_mixed_case_target() {
+            let mut server = Server::new_async().await;
+            let fetch = server
+                .mock("GET", "/zones/z/dns_records")
+                .match_query(Matcher::UrlEncoded(
+                    "name".into(),
+                    "test.example.com".into(),
+                ))
+                .with_header("content-type", "application/json")
+                .with_body(records_body(json!([a_record("1.1.1.1")])))
+                .create_async()
+                .await;
+            let update = server
+                .mock("PUT", "/zones/z/dns_records/r1")
+                .with_header("content-type", "application/json")
+                .with_body(json!({ "success": true, "errors": [], "result": {} }).to_string())
+                .create_async()
+                .await;
+
+            let request = build_request(&server, "Test.Example.COM", "").await;
+            let result = request
+                .request(&UUID.to_string(), "8.8.8.8".to_string(), "test", false)
+                .await
+                .unwrap();
+            assert_eq!(result.status(), UpdateStatus::Updated);
+            fetch.assert_async().await;
+            update.assert_async().await;
+        }
+
+        #[tokio::test]
+        async fn test_request_unchanged() {
+            let mut server = Server::new_async().await;
+            let fetch = server
+                .mock("GET", "/zones/z/dns_records")
+                .match_query(Matcher::Any)
+                .with_header("content-type", "application/json")
+                .with_body(records_body(json!([a_record("8.8.8.8")])))
+                .create_async()
+                .await;
+            let update = server
+                .mock("PUT", Matcher::Any)
+                .expect(0)
+                .create_async()
+                .await;
+
+            let request = build_request(&server, "test.example.com", "").await;
+            let result = request
+                .request(&UUID.to_string(), "8.8.8.8".to_string(), "test", false)
+                .await
+                .unwrap();
+            assert_eq!(result.status(), UpdateStatus::Unchanged);
+            fetch.assert_async().await;
+            update.assert_async().await;
+        }
+
+        #[tokio::test]
+        async fn test_request_equivalent_ipv6() {
+            let mut server = Server::new_async().await;
+            let mut record = a_record("2606:4700:0000:0000:0000:0000:0000:0001");
+            record["type"] = "AAAA".into();
+            let fetch = server
+                .mock("GET", "/zones/z/dns_records")
+                .match_query(Matcher::UrlEncoded("type".into(), "AAAA".into()))
+                .with_header("content-type", "application/json")
+                .with_body(records_body(json!([record])))
+                .expect(2)
+                .create_async()
+                .await;
+            let update = server
+                .mock("PUT", Matcher::Any)
+                .expect(0)
+                .create_async()
+                .await;
+
+            let request = build_request(&server, "test.example.com", "").await;
+            for ip in ["2606:4700::1", "2606:4700:0:0:0:0:0:0001"] {
+                let result = request
+                    .request(&UUID.to_string(), ip.to_string(), "test", false)
+                    .await
+                    .unwrap();
+                assert_eq!(result.status(), UpdateStatus::Unchanged, "{}", ip);
+            }
+            fetch.assert_async().await;
+            update.assert_async().await;
+        }
+
+        #[tokio::test]
+        async fn test_request_cloudflare_error() {
+            let mut server = Server::new_async().await;
+            server
+                .mock("GET", "/zones/z/dns_records")
+                .match_query(Matcher::Any)
+                .with_status(400)
+                .with_header("content-type", "application/json")
+                .with_body(
+                    json!({
+                        "success": false,
+                        "errors": [{ "code": 9109, "message": "Invalid access token" }],
+                        "result": null,
+                    })
+                    .to_string(),
+                )
+                .expect(2)
+                .create_async()
+                .await;
+
+            // Error is kind only by default
+            let request = build_request(&server, "test.example.com", "").await;
+            let result = request
+                .request(&UUID.to_string(), "8.8.8.8".to_string(), "test", false)
+                .await
+                .unwrap();
+            assert_eq!(result.status(), UpdateStatus::Unchanged);
+            assert_eq!(result.zones()[0].error.as_deref(), Some("cloudflare"));
+
+            let mut request = build_request(&server, "test.example.com", "").await;
+            request.expose_api_error = true;
+            let ret = request
+                .request(&UUID.to_string(), "8.8.8.8".to_string(), "test", false)
+                .await;
+            assert!(matches!(ret, Err(ApiError::Cloudflare { code: 9109, .. })));
         }
     }
 }
 
 mod api_error {
     use axum::http::StatusCode;
+    use axum::response::{IntoResponse, Response};
+    use axum::Json;
     use log::error;
+    use serde_json::json;
+    use std::fmt::Formatter;
 
     #[derive(Debug)]
     pub enum ApiError {
+        BadRequest,
         Forbidden,
+        NotFound,
+        ServiceUnavailable,
+        GatewayTimeout,
+        TooManyRequests,
+        PayloadTooLarge,
+        // Status responded by relay upstream
+        Upstream(StatusCode),
+        Cloudflare { code: i64, message: String },
         Other(anyhow::Error),
     }
 
@@ -373,16 +2709,99 @@ mod api_error {
             Self::Forbidden
         }
 
-        pub fn into_response(self) -> (StatusCode, &'static str) {
+        pub fn cloudflare(code: i64, message: String) -> Self {
+            Self::Cloudflare { code, message }
+        }
+
+        // Failure may succeed if retried later
+        pub fn is_transient(&self) -> bool {
             match self {
-                ApiError::Forbidden => (StatusCode::FORBIDDEN, "403 Forbidden\n"),
-                ApiError::Other(e) => {
-                    error!("{}", e);
-                    (
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        "500 Internal server error\n",
-                    )
+                ApiError::ServiceUnavailable | ApiError::GatewayTimeout => true,
+                ApiError::Upstream(status) => {
+                    status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+                }
+                _ => false,
+            }
+        }
+
+        pub fn status_code(&self) -> StatusCode {
+            match self {
+                ApiError::BadRequest => StatusCode::BAD_REQUEST,
+                ApiError::Forbidden => StatusCode::FORBIDDEN,
+                ApiError::NotFound => StatusCode::NOT_FOUND,
+                ApiError::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+                ApiError::GatewayTimeout => StatusCode::GATEWAY_TIMEOUT,
+                ApiError::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+                ApiError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+                ApiError::Upstream(status) => *status,
+                ApiError::Cloudflare { .. } => StatusCode::BAD_GATEWAY,
+                ApiError::Other(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            }
+        }
+
+        // Machine readable error kind in JSON response
+        pub fn kind(&self) -> &'static str {
+            match self {
+                ApiError::BadRequest => "bad_request",
+                ApiError::Forbidden => "forbidden",
+                ApiError::NotFound => "not_found",
+                ApiError::ServiceUnavailable => "unavailable",
+                ApiError::GatewayTimeout => "timeout",
+                ApiError::TooManyRequests => "rate_limited",
+                ApiError::PayloadTooLarge => "payload_too_large",
+                ApiError::Upstream(_) => "upstream",
+                ApiError::Cloudflare { .. } => "cloudflare",
+                ApiError::Other(_) => "internal",
+            }
+        }
+
+        // Message can be sent to client, internal error detail is hidden
+        pub fn message(&self) -> String {
+            match self {
+                ApiError::Other(_) => "Internal server error".to_string(),
+                _ => self.to_string(),
+            }
+        }
+
+        // Plain text by default, or `{"error": kind, "message": message}` if client accept JSON
+        pub fn into_response_with(self, json: bool) -> Response {
+            if let ApiError::Other(ref e) = self {
+                error!("{}", e);
+            }
+            let status = self.status_code();
+            if json {
+                (
+                    status,
+                    Json(json!({ "error": self.kind(), "message": self.message() })),
+                )
+                    .into_response()
+            } else {
+                (status, format!("{} {}\n", status.as_u16(), self.message())).into_response()
+            }
+        }
+    }
+
+    impl IntoResponse for ApiError {
+        fn into_response(self) -> Response {
+            self.into_response_with(false)
+        }
+    }
+
+    impl std::fmt::Display for ApiError {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            match self {
+                ApiError::BadRequest => write!(f, "Bad request"),
+                ApiError::Forbidden => write!(f, "Forbidden"),
+                ApiError::NotFound => write!(f, "Not found"),
+                ApiError::ServiceUnavailable => write!(f, "Service unavailable"),
+                ApiError::GatewayTimeout => write!(f, "Gateway timeout"),
+                ApiError::TooManyRequests => write!(f, "Too many requests"),
+                ApiError::PayloadTooLarge => write!(f, "Payload too large"),
+                ApiError::Upstream(status) => write!(f, "Upstream responded {}", status),
+                ApiError::Cloudflare { code, message } => {
+                    write!(f, "Cloudflare error {}: {}", code, message)
                 }
+                ApiError::Other(e) => write!(f, "{}", e),
             }
         }
     }
@@ -394,5 +2813,5 @@ mod api_error {
     }
 }
 
-pub use api::ApiRequest;
+pub use api::{ApiRequest, UpdateResult, UpdateStatus, REQUEST_ID_HEADER};
 pub use api_error::ApiError;
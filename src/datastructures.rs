@@ -1,12 +1,43 @@
 mod config {
-    use anyhow::anyhow;
+    use axum::http::{HeaderValue, StatusCode};
+    use log::{Level, LevelFilter};
+    use ring::digest;
     use serde_derive::Deserialize;
+    use std::collections::HashMap;
     use std::fmt::Formatter;
+    use std::net::IpAddr;
+    use std::str::FromStr;
+    use std::time::Duration;
 
-    #[derive(Clone, Debug, Deserialize)]
+    // 1 means automatic in cloudflare
+    const DEFAULT_TTL: i32 = 1;
+    const DEFAULT_MAX_BODY_BYTES: usize = 4096;
+    // Batch request body limit is multiple of single request limit
+    const BATCH_BODY_LIMIT_FACTOR: usize = 64;
+    const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+    const DEFAULT_QUEUE_TTL_SECS: u64 = 3600;
+    const DEFAULT_TARGET_TIMEOUT_SECS: u64 = 5;
+    const DEFAULT_MAX_CONCURRENT_CF_REQUESTS: usize = 10;
+    const DEFAULT_SHUTDOWN_GRACE_SECS: u64 = 10;
+    const DEFAULT_WATCHER_POLL_INTERVAL_SECS: u64 = 5;
+    const DEFAULT_BATCH_CONCURRENCY: usize = 4;
+    const MAX_FREE_IDENTIFIER_LEN: usize = 64;
+    const DEFAULT_OWNERSHIP_NAME: &str = "_owner.{target}";
+
+    #[derive(Clone, Debug, Deserialize, PartialEq)]
     pub struct ZoneMapper {
         domain: String,
         zone: String,
+        // Override global token for this zone
+        token: Option<String>,
+        // Use token of named profile, conflict with token
+        profile: Option<String>,
+        // Override global record comment for this zone
+        comment: Option<String>,
+        // Also keep MX record of each target pointing to itself with this priority
+        mx_priority: Option<u16>,
+        // Update this record instead of searching by name, only for target same as domain
+        record_id: Option<String>,
     }
 
     impl ZoneMapper {
@@ -16,15 +47,95 @@ mod config {
         pub fn zone(&self) -> &str {
             &self.zone
         }
-        pub fn new(domain: String, zone: String) -> Self {
-            Self { domain, zone }
+        pub fn token(&self) -> &str {
+            self.token.as_deref().unwrap_or_default()
+        }
+        pub fn token_or<'a>(&'a self, default: &'a str) -> &'a str {
+            self.token.as_deref().unwrap_or(default)
+        }
+        pub fn comment(&self) -> &Option<String> {
+            &self.comment
+        }
+        pub fn mx_priority(&self) -> Option<u16> {
+            self.mx_priority
+        }
+        pub fn record_id(&self) -> Option<&str> {
+            self.record_id.as_deref()
+        }
+        pub fn new(
+            domain: String,
+            zone: String,
+            token: String,
+            comment: Option<String>,
+            mx_priority: Option<u16>,
+            record_id: Option<String>,
+        ) -> Self {
+            Self {
+                domain,
+                zone,
+                token: Some(token),
+                profile: None,
+                comment,
+                mx_priority,
+                record_id,
+            }
         }
     }
 
-    #[derive(Clone, Debug, Deserialize)]
+    #[derive(Clone, Debug, Deserialize, PartialEq)]
+    pub struct Profile {
+        token: String,
+    }
+
+    impl Profile {
+        pub fn token(&self) -> &str {
+            &self.token
+        }
+    }
+
+    // Update target only if companion TXT record holds this instance's owner ID
+    #[derive(Clone, Debug, Deserialize, PartialEq)]
+    pub struct OwnershipCheck {
+        // `{target}` is replaced by target domain, default is `_owner.{target}`
+        name: Option<String>,
+        owner: String,
+    }
+
+    impl OwnershipCheck {
+        pub fn name(&self, target: &str) -> String {
+            self.name
+                .as_deref()
+                .unwrap_or(DEFAULT_OWNERSHIP_NAME)
+                .replace("{target}", target)
+        }
+        pub fn owner(&self) -> &str {
+            &self.owner
+        }
+    }
+
+    #[derive(Clone, Debug, Deserialize, PartialEq)]
     pub struct ClientMapper {
         uuid: String,
         target: Vec<String>,
+        // Empty means no restriction
+        #[serde(default)]
+        allowed_cidrs: Vec<String>,
+        // A, AAAA or both, empty means detect by submitted IP
+        #[serde(default)]
+        record_types: Vec<String>,
+        // Override global `column_ip` for this client
+        column: Option<Columns>,
+        // Require HMAC-SHA256 signed request if set
+        hmac_key: Option<String>,
+        // Every target is updated at all providers, default is cloudflare only
+        #[serde(default)]
+        providers: Vec<Provider>,
+    }
+
+    #[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+    #[serde(rename_all = "kebab-case")]
+    pub enum Provider {
+        Cloudflare,
     }
 
     impl ClientMapper {
@@ -34,9 +145,46 @@ mod config {
         pub fn target(&self) -> &Vec<String> {
             &self.target
         }
+        pub fn allowed_cidrs(&self) -> &Vec<String> {
+            &self.allowed_cidrs
+        }
+        pub fn record_types(&self) -> &Vec<String> {
+            &self.record_types
+        }
+        pub fn column(&self) -> Option<Vec<String>> {
+            self.column.clone().map(Columns::into_vec)
+        }
+        pub fn hmac_key(&self) -> &Option<String> {
+            &self.hmac_key
+        }
+        pub fn providers(&self) -> Vec<Provider> {
+            if self.providers.is_empty() {
+                return vec![Provider::Cloudflare];
+            }
+            let mut providers = self.providers.clone();
+            providers.dedup();
+            providers
+        }
     }
 
-    #[derive(Clone, Debug, Default, Deserialize)]
+    // Single header or list of headers tried in order
+    #[derive(Clone, Debug, Deserialize, PartialEq)]
+    #[serde(untagged)]
+    pub enum Columns {
+        Single(String),
+        Multiple(Vec<String>),
+    }
+
+    impl Columns {
+        pub fn into_vec(self) -> Vec<String> {
+            match self {
+                Columns::Single(column) => vec![column],
+                Columns::Multiple(columns) => columns,
+            }
+        }
+    }
+
+    #[derive(Clone, Debug, Default, Deserialize, PartialEq)]
     pub struct ClientMapperSingle {
         uuid: String,
         target: Option<String>,
@@ -55,12 +203,110 @@ mod config {
         }
     }
 
-    #[derive(Clone, Debug, Default, Deserialize)]
+    #[derive(Clone, Debug, Deserialize, PartialEq)]
+    struct RelayClientsFile {
+        clients: Vec<ClientMapperSingle>,
+    }
+
+    #[derive(Clone, Debug, Default, Deserialize, PartialEq)]
     pub struct Relay {
         enabled: bool,
         target: Vec<String>,
+        #[serde(default)]
         clients: Vec<ClientMapperSingle>,
+        // Extra clients (`[[clients]]` array) read from file, merged with inline clients
+        clients_file: Option<String>,
         proxy: Option<String>,
+        // Queue failed forwards and retry in background
+        #[serde(default)]
+        queue: bool,
+        queue_capacity: Option<usize>,
+        queue_ttl_secs: Option<u64>,
+        // Disable to open new connection for every forward, useful if upstream IP changes
+        connection_reuse: Option<bool>,
+        // Forward with HTTP/2 without negotiation, upstream (and proxy) must support HTTP/2
+        #[serde(default)]
+        http2_prior_knowledge: bool,
+        // Response of GET without IP header: `strict` (default, 403) or `lenient` (200, no update)
+        #[serde(default)]
+        missing_header: MissingHeader,
+        // `DISABLE_URL_WARNING` environment variable take precedence
+        #[serde(default)]
+        suppress_url_warning: bool,
+        // Forward to every target concurrently instead of first reachable one
+        #[serde(default)]
+        broadcast: bool,
+        target_timeout_secs: Option<u64>,
+    }
+
+    #[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+    #[serde(rename_all = "kebab-case")]
+    pub enum MissingHeader {
+        #[default]
+        Strict,
+        Lenient,
+    }
+
+    // Identifier of client in route path, `free` allows human-readable name or API key
+    #[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+    #[serde(rename_all = "kebab-case")]
+    pub enum IdentifierMode {
+        #[default]
+        Uuid,
+        Free,
+    }
+
+    impl IdentifierMode {
+        pub fn is_valid(self, id: &str) -> bool {
+            match self {
+                IdentifierMode::Uuid => uuid::Uuid::from_str(id).is_ok(),
+                IdentifierMode::Free => {
+                    (1..=MAX_FREE_IDENTIFIER_LEN).contains(&id.len())
+                        && id
+                            .bytes()
+                            .all(|c| c.is_ascii_alphanumeric() || b"-_.".contains(&c))
+                }
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    pub enum ConfigError {
+        // Configure file or file referenced by it (token_file, clients_file) can't be read
+        Io {
+            path: String,
+            source: std::io::Error,
+        },
+        // TOML syntax or schema error, path is None if configure is not read from file
+        Parse {
+            path: Option<String>,
+            source: toml::de::Error,
+        },
+        Validation(String),
+    }
+
+    impl std::fmt::Display for ConfigError {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            match self {
+                ConfigError::Io { path, .. } => write!(f, "Unable read {:?}", path),
+                ConfigError::Parse {
+                    path: Some(path), ..
+                } => write!(f, "Unable parse {:?}", path),
+                ConfigError::Parse { path: None, .. } => write!(f, "Unable parse configure toml"),
+                ConfigError::Validation(message) => write!(f, "Config check failed. {}", message),
+            }
+        }
+    }
+
+    // Message of underlying error is not included in Display, print with `{:#}` of anyhow
+    impl std::error::Error for ConfigError {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self {
+                ConfigError::Io { source, .. } => Some(source),
+                ConfigError::Parse { source, .. } => Some(source),
+                ConfigError::Validation(_) => None,
+            }
+        }
     }
 
     impl Relay {
@@ -77,22 +323,119 @@ mod config {
         pub fn proxy(&self) -> &Option<String> {
             &self.proxy
         }
+        pub fn clients_file(&self) -> &Option<String> {
+            &self.clients_file
+        }
+        pub fn queue(&self) -> bool {
+            self.queue
+        }
+        pub fn queue_capacity(&self) -> usize {
+            self.queue_capacity.unwrap_or(DEFAULT_QUEUE_CAPACITY)
+        }
+        pub fn queue_ttl(&self) -> Duration {
+            Duration::from_secs(self.queue_ttl_secs.unwrap_or(DEFAULT_QUEUE_TTL_SECS))
+        }
+        pub fn connection_reuse(&self) -> bool {
+            self.connection_reuse.unwrap_or(true)
+        }
+        pub fn http2_prior_knowledge(&self) -> bool {
+            self.http2_prior_knowledge
+        }
+        pub fn missing_header(&self) -> MissingHeader {
+            self.missing_header
+        }
+        pub fn suppress_url_warning(&self) -> bool {
+            self.suppress_url_warning
+        }
+        pub fn broadcast(&self) -> bool {
+            self.broadcast
+        }
+        pub fn target_timeout(&self) -> Duration {
+            Duration::from_secs(
+                self.target_timeout_secs
+                    .unwrap_or(DEFAULT_TARGET_TIMEOUT_SECS),
+            )
+        }
     }
 
-    #[derive(Clone, Debug, Deserialize)]
+    #[derive(Clone, Debug, Deserialize, PartialEq)]
     pub struct Config {
+        // SHA-256 of raw configure file, for auditing running configure in log
+        #[serde(skip)]
+        hash: String,
         server: Server,
         #[serde(default)]
         client: Vec<ClientMapper>,
         // Can be empty if relay
         #[serde(default)]
         zones: Vec<ZoneMapper>,
+        // Named tokens which can be referenced by zones
+        #[serde(default)]
+        profiles: HashMap<String, Profile>,
+        ownership_check: Option<OwnershipCheck>,
+        // Refuse to update record whose name doesn't start with prefix, guardrail for shared zones
+        managed_name_prefix: Option<String>,
         #[serde(default)]
         relay: Relay,
         // Can be option if relay
         #[serde(default)]
         token: String,
-        column_ip: Option<String>,
+        // Read token from file (e.g. docker secrets), conflict with inline token
+        token_file: Option<String>,
+        column_ip: Option<Columns>,
+        // Header of IPv6 address for dual-stack clients, e.g. `X-Real-IP6`.
+        // If present, IPv6 address is updated (or forwarded) along with address of `column_ip`
+        column_ipv6: Option<Columns>,
+        // Use TCP peer address as client IP, `column_ip` header will be ignored.
+        // Only enable this if server is directly exposed (not behind reverse proxy),
+        // otherwise the proxy address will be used. In relay mode, the peer address
+        // is forwarded to upstream instead of header value.
+        #[serde(default)]
+        use_peer_addr: bool,
+        // Cloudflare API base url, default is https://api.cloudflare.com/client/v4
+        api_base: Option<String>,
+        // Create record which not exists, otherwise target without record fails (e.g. typo)
+        #[serde(default)]
+        create_missing_records: bool,
+        // TTL and proxied status for record which not exists and need to be created
+        default_ttl: Option<i32>,
+        default_proxied: Option<bool>,
+        // Verify cloudflare token on startup, ignored in relay mode
+        #[serde(default)]
+        verify_token: bool,
+        // Refuse flapping back to last two values within interval after update
+        min_update_interval_secs: Option<u64>,
+        // Re-assert last pushed IP of every client periodically, to correct out-of-band edit
+        reconcile_interval_secs: Option<u64>,
+        // Last pushed IP of every client is saved on shutdown and loaded on startup
+        state_file: Option<String>,
+        // Comment attached to updated records, existing comment is kept if not set
+        comment: Option<String>,
+        // Serve admin and debug routes on separate address
+        admin: Option<AdminServer>,
+        max_concurrent_cf_requests: Option<usize>,
+        // User-Agent for cloudflare and relay requests, default is `cautious-waffle <version>`
+        user_agent: Option<String>,
+        // Proxy for cloudflare requests, hosts in `no_proxy` (comma separated) bypass it
+        proxy: Option<String>,
+        no_proxy: Option<String>,
+        // Turn suspicious configure warnings into errors
+        #[serde(default)]
+        strict_config: bool,
+        // Zone IDs which can be used by zones, empty means no restriction
+        #[serde(default)]
+        allowed_zones: Vec<String>,
+        // Source address of outbound requests, for multi-homed hosts
+        local_address: Option<String>,
+        // Overall and connect timeout of cloudflare requests, overall default is 5 seconds
+        timeout_secs: Option<u64>,
+        connect_timeout_secs: Option<u64>,
+        // `native` (default) or `poll`, poll is required on NFS/SMB
+        watcher_mode: Option<String>,
+        watcher_poll_interval_secs: Option<u64>,
+        // Log level of successful update (default info) and unchanged push (default debug)
+        update_log_level: Option<String>,
+        unchanged_log_level: Option<String>,
     }
 
     impl Config {
@@ -108,33 +451,374 @@ mod config {
             self.server.to_string()
         }
 
+        pub fn get_admin_bind(&self) -> Option<String> {
+            self.admin.as_ref().map(|admin| admin.to_string())
+        }
+
         pub fn zones(&self) -> &Vec<ZoneMapper> {
             &self.zones
         }
 
+        pub fn hash(&self) -> &str {
+            &self.hash
+        }
+
         pub fn is_relay_mode(&self) -> bool {
-            return self.relay.enabled();
+            self.relay.enabled()
         }
 
         pub fn relay(self) -> Relay {
             self.relay
         }
-        pub fn column_ip(&self) -> &Option<String> {
-            &self.column_ip
+        pub fn relay_clients_file(&self) -> &Option<String> {
+            self.relay.clients_file()
+        }
+        pub fn column_ip(&self) -> Option<Vec<String>> {
+            self.column_ip.clone().map(Columns::into_vec)
+        }
+        pub fn column_ipv6(&self) -> Vec<String> {
+            self.column_ipv6
+                .clone()
+                .map(Columns::into_vec)
+                .unwrap_or_default()
+        }
+        pub fn use_peer_addr(&self) -> bool {
+            self.use_peer_addr
+        }
+        pub fn api_base(&self) -> &Option<String> {
+            &self.api_base
+        }
+        pub fn default_ttl(&self) -> i32 {
+            self.default_ttl.unwrap_or(DEFAULT_TTL)
+        }
+        pub fn default_proxied(&self) -> bool {
+            self.default_proxied.unwrap_or_default()
+        }
+        pub fn create_missing_records(&self) -> bool {
+            self.create_missing_records
+        }
+        pub fn verify_token(&self) -> bool {
+            self.verify_token
+        }
+        pub fn comment(&self) -> &Option<String> {
+            &self.comment
+        }
+        pub fn user_agent(&self) -> &Option<String> {
+            &self.user_agent
+        }
+        pub fn proxy(&self) -> &Option<String> {
+            &self.proxy
+        }
+        pub fn no_proxy(&self) -> &Option<String> {
+            &self.no_proxy
+        }
+        pub fn strict_config(&self) -> bool {
+            self.strict_config
+        }
+        pub fn allowed_zones(&self) -> &Vec<String> {
+            &self.allowed_zones
+        }
+        // Checked while loading configure
+        pub fn update_log_level(&self) -> Level {
+            self.update_log_level
+                .as_deref()
+                .and_then(|level| level.parse().ok())
+                .unwrap_or(Level::Info)
+        }
+        pub fn unchanged_log_level(&self) -> Level {
+            self.unchanged_log_level
+                .as_deref()
+                .and_then(|level| level.parse().ok())
+                .unwrap_or(Level::Debug)
+        }
+        pub fn watcher_mode(&self) -> Option<&str> {
+            self.watcher_mode.as_deref()
+        }
+        pub fn watcher_poll_interval(&self) -> Duration {
+            Duration::from_secs(
+                self.watcher_poll_interval_secs
+                    .filter(|secs| *secs > 0)
+                    .unwrap_or(DEFAULT_WATCHER_POLL_INTERVAL_SECS),
+            )
+        }
+        // Checked while loading configure
+        pub fn local_address(&self) -> Option<IpAddr> {
+            self.local_address
+                .as_ref()
+                .and_then(|addr| addr.parse().ok())
+        }
+        pub fn ownership_check(&self) -> &Option<OwnershipCheck> {
+            &self.ownership_check
+        }
+        pub fn managed_name_prefix(&self) -> Option<&str> {
+            self.managed_name_prefix.as_deref()
+        }
+        pub fn timeout_secs(&self) -> Option<u64> {
+            self.timeout_secs.filter(|secs| *secs > 0)
+        }
+        pub fn connect_timeout(&self) -> Option<Duration> {
+            self.connect_timeout_secs
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs)
+        }
+        pub fn max_concurrent_cf_requests(&self) -> usize {
+            self.max_concurrent_cf_requests
+                .filter(|n| *n > 0)
+                .unwrap_or(DEFAULT_MAX_CONCURRENT_CF_REQUESTS)
+        }
+        pub fn state_file(&self) -> Option<&str> {
+            self.state_file.as_deref()
+        }
+        pub fn reconcile_interval(&self) -> Option<Duration> {
+            self.reconcile_interval_secs
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs)
+        }
+
+        pub fn min_update_interval(&self) -> Option<Duration> {
+            self.min_update_interval_secs
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs)
+        }
+
+        pub async fn try_from_file(location: &str) -> Result<Self, ConfigError> {
+            let content = tokio::fs::read_to_string(&location)
+                .await
+                .map_err(|source| ConfigError::Io {
+                    path: location.to_string(),
+                    source,
+                })?;
+            // Relative paths in configure are relative to configure file
+            let base = std::path::Path::new(location).parent();
+            Self::try_from_toml_with_base(&content, base)
+                .await
+                .map_err(|e| match e {
+                    ConfigError::Parse { path: None, source } => ConfigError::Parse {
+                        path: Some(location.to_string()),
+                        source,
+                    },
+                    e => e,
+                })
+        }
+
+        // Build configure from toml string, referenced files (token_file, ...) are still read from disk
+        pub async fn try_from_toml(content: &str) -> Result<Self, ConfigError> {
+            Self::try_from_toml_with_base(content, None).await
         }
 
-        pub async fn try_from_file(location: &str) -> anyhow::Result<Self> {
-            let config: Self = toml::from_str(
-                &tokio::fs::read_to_string(&location)
+        async fn try_from_toml_with_base(
+            content: &str,
+            base: Option<&std::path::Path>,
+        ) -> Result<Self, ConfigError> {
+            let mut config: Self = toml::from_str(content)
+                .map_err(|source| ConfigError::Parse { path: None, source })?;
+            config.hash = hex::encode(digest::digest(&digest::SHA256, content.as_bytes()));
+
+            if let Some(base) = base {
+                for path in [
+                    &mut config.token_file,
+                    &mut config.relay.clients_file,
+                    &mut config.state_file,
+                ] {
+                    resolve_path(base, path);
+                }
+            }
+
+            if let Some(ref token_file) = config.token_file {
+                if !config.token.is_empty() {
+                    return Err(ConfigError::Validation(
+                        "token and token_file should not be specified at the same time".to_string(),
+                    ));
+                }
+                config.token = tokio::fs::read_to_string(token_file)
                     .await
-                    .map_err(|e| anyhow!("Unable read {:?}: {:?}", &location, e))?,
-            )
-            .map_err(|e| anyhow!("Unable serialize configure toml: {:?}", e))?;
+                    .map_err(|source| ConfigError::Io {
+                        path: token_file.clone(),
+                        source,
+                    })?
+                    .trim_end()
+                    .to_string();
+            }
+
+            if let Some(ref clients_file) = config.relay.clients_file {
+                let clients: RelayClientsFile =
+                    toml::from_str(&tokio::fs::read_to_string(clients_file).await.map_err(
+                        |source| ConfigError::Io {
+                            path: clients_file.clone(),
+                            source,
+                        },
+                    )?)
+                    .map_err(|source| ConfigError::Parse {
+                        path: Some(clients_file.clone()),
+                        source,
+                    })?;
+                config.relay.clients.extend(clients.clients);
+            }
+
+            for zone in config.zones.iter_mut() {
+                let Some(ref name) = zone.profile else {
+                    continue;
+                };
+                if zone.token.is_some() {
+                    return Err(ConfigError::Validation(format!(
+                        "token and profile of zone {:?} should not be specified at the same time",
+                        zone.domain
+                    )));
+                }
+                let profile = config.profiles.get(name).ok_or_else(|| {
+                    ConfigError::Validation(format!(
+                        "profile {:?} of zone {:?} is not defined",
+                        name, zone.domain
+                    ))
+                })?;
+                zone.token = Some(profile.token().to_string());
+            }
+
+            if let Some(ref check) = config.ownership_check {
+                if check.owner.is_empty() {
+                    return Err(ConfigError::Validation(
+                        "ownership_check.owner should not be empty".to_string(),
+                    ));
+                }
+            }
+
+            // Prefix is start of first label of record name
+            if let Some(ref prefix) = config.managed_name_prefix {
+                if prefix.is_empty()
+                    || prefix.len() > 63
+                    || prefix.starts_with('-')
+                    || !prefix
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                {
+                    return Err(ConfigError::Validation(format!(
+                        "managed_name_prefix {:?} is not valid DNS label fragment",
+                        prefix
+                    )));
+                }
+                let prefix = prefix.to_lowercase();
+                if let Some(target) = config
+                    .client
+                    .iter()
+                    .flat_map(|client| client.target.iter())
+                    .find(|target| !target.to_lowercase().starts_with(&prefix))
+                {
+                    return Err(ConfigError::Validation(format!(
+                        "target {:?} doesn't start with managed_name_prefix {:?}",
+                        target, prefix
+                    )));
+                }
+            }
+
+            // Client unreachable by route is configure mistake
+            let identifier_mode = config.identifier_mode();
+            if let Some(id) = config
+                .client
+                .iter()
+                .map(|client| client.uuid().as_str())
+                .chain(config.relay.clients.iter().map(|client| client.uuid()))
+                .find(|id| !identifier_mode.is_valid(id))
+            {
+                return Err(match identifier_mode {
+                    IdentifierMode::Uuid => ConfigError::Validation(format!(
+                        "client identifier {:?} is not UUID, set server.identifier_mode to free to use other identifier",
+                        id
+                    )),
+                    IdentifierMode::Free => ConfigError::Validation(format!(
+                        "client identifier {:?} should be 1 to {} letters, digits, `-`, `_` or `.`",
+                        id,
+                        MAX_FREE_IDENTIFIER_LEN
+                    )),
+                });
+            }
+
+            // Clients of file are merged above, so relay without any client is caught here
+            // instead of while building relay
+            if config.is_relay_mode() && config.relay.clients.is_empty() {
+                return Err(ConfigError::Validation("relay is enabled, but no client is specified in relay.clients or relay.clients_file".to_string()));
+            }
 
             if !config.check_config() {
-                return Err(anyhow!(
-                    "Config check failed. if not use relay mode, please specify token and zone"
-                ));
+                return Err(ConfigError::Validation("if not use relay mode, please specify zone, client and token (global or for every zone)".to_string()));
+            }
+
+            if let Some(status) = config.server.fallback_status {
+                if StatusCode::from_u16(status).is_err() || !(100..600).contains(&status) {
+                    return Err(ConfigError::Validation(format!(
+                        "fallback_status {} is not a valid HTTP status",
+                        status
+                    )));
+                }
+            }
+
+            for origin in &config.server.cors_allowed_origins {
+                if HeaderValue::from_str(origin).is_err() {
+                    return Err(ConfigError::Validation(format!(
+                        "cors_allowed_origins {:?} is not a valid header value",
+                        origin
+                    )));
+                }
+            }
+
+            if let Some(ref user_agent) = config.user_agent {
+                if HeaderValue::from_str(user_agent).is_err() {
+                    return Err(ConfigError::Validation(format!(
+                        "user_agent {:?} is not a valid header value",
+                        user_agent
+                    )));
+                }
+            }
+
+            if let Some(ref local_address) = config.local_address {
+                local_address.parse::<IpAddr>().map_err(|e| {
+                    ConfigError::Validation(format!(
+                        "local_address {:?} is invalid: {:?}",
+                        local_address, e
+                    ))
+                })?;
+            }
+
+            for level in [&config.update_log_level, &config.unchanged_log_level]
+                .into_iter()
+                .flatten()
+            {
+                if level.parse::<Level>().is_err() {
+                    return Err(ConfigError::Validation(format!(
+                        "{:?} is not a valid log level",
+                        level
+                    )));
+                }
+            }
+
+            if let Some(ref level) = config.server.log_level {
+                if level.parse::<LevelFilter>().is_err() {
+                    return Err(ConfigError::Validation(format!(
+                        "server.log_level {:?} is not a valid log level",
+                        level
+                    )));
+                }
+            }
+
+            if let Some(ref mode) = config.watcher_mode {
+                if !["native", "poll"].contains(&mode.as_str()) {
+                    return Err(ConfigError::Validation(format!(
+                        "watcher_mode should be native or poll, but got {:?}",
+                        mode
+                    )));
+                }
+            }
+
+            if let Some(ref proxy) = config.proxy {
+                reqwest::Proxy::all(proxy).map_err(|e| {
+                    ConfigError::Validation(format!("proxy {:?} is invalid: {:?}", proxy, e))
+                })?;
+            }
+
+            if !Self::check_ttl(config.default_ttl()) {
+                return Err(ConfigError::Validation(format!(
+                    "default_ttl should be 1 (automatic) or between 60 and 86400, but got {}",
+                    config.default_ttl()
+                )));
             }
 
             Ok(config)
@@ -143,31 +827,372 @@ mod config {
         #[must_use]
         fn check_config(&self) -> bool {
             self.is_relay_mode()
-                || (!self.token.is_empty() && !self.zones.is_empty() && !self.client.is_empty())
+                || (!self.zones.is_empty()
+                    && !self.client.is_empty()
+                    && self
+                        .zones
+                        .iter()
+                        .all(|zone| !zone.token_or(&self.token).is_empty()))
+        }
+
+        fn check_ttl(ttl: i32) -> bool {
+            ttl == 1 || (60..=86400).contains(&ttl)
         }
 
         pub fn enable_query(&self) -> bool {
             self.server.enable_query()
         }
+
+        pub fn expose_api_error(&self) -> bool {
+            self.server.expose_api_error()
+        }
+
+        pub fn allow_delete(&self) -> bool {
+            self.server.allow_delete()
+        }
+
+        pub fn allow_batch(&self) -> bool {
+            self.server.allow_batch()
+        }
+
+        pub fn fallback(&self) -> (StatusCode, String) {
+            self.server.fallback()
+        }
+
+        pub fn max_body_bytes(&self) -> usize {
+            self.server.max_body_bytes()
+        }
+
+        pub fn suppress_query_warning(&self) -> bool {
+            self.server.suppress_query_warning()
+        }
+
+        pub fn opaque_unknown_client(&self) -> bool {
+            self.server.opaque_unknown_client()
+        }
+
+        pub fn identifier_mode(&self) -> IdentifierMode {
+            self.server.identifier_mode()
+        }
+
+        pub fn allow_private_ips(&self) -> bool {
+            self.server.allow_private_ips()
+        }
+
+        pub fn allow_lookup(&self) -> bool {
+            self.server.allow_lookup()
+        }
+
+        pub fn expose_version(&self) -> bool {
+            self.server.expose_version()
+        }
+
+        pub fn admin_token(&self) -> &Option<String> {
+            self.server.admin_token()
+        }
+
+        pub fn redact_headers(&self) -> &Vec<String> {
+            self.server.redact_headers()
+        }
+
+        pub fn batch_concurrency(&self) -> usize {
+            self.server.batch_concurrency()
+        }
+
+        pub fn global_rate_limit(&self) -> Option<u32> {
+            self.server.global_rate_limit()
+        }
+
+        pub fn log_level(&self) -> Option<LevelFilter> {
+            self.server.log_level()
+        }
+
+        pub fn cors_allowed_origins(&self) -> &Vec<String> {
+            self.server.cors_allowed_origins()
+        }
+
+        pub fn shutdown_grace(&self) -> Duration {
+            self.server.shutdown_grace()
+        }
+
+        pub fn request_timeout_secs(&self) -> Option<u64> {
+            self.server.request_timeout_secs()
+        }
+
+        pub fn force_exit(&self) -> ForceExit {
+            self.server.force_exit()
+        }
+
+        pub fn tcp_backlog(&self) -> Option<i32> {
+            self.server.tcp_backlog()
+        }
+
+        pub fn tcp_keepalive(&self) -> Option<Duration> {
+            self.server.tcp_keepalive()
+        }
+
+        pub fn accept_h2c(&self) -> bool {
+            self.server.accept_h2c()
+        }
+
+        pub fn max_batch_body_bytes(&self) -> usize {
+            self.server.max_body_bytes() * BATCH_BODY_LIMIT_FACTOR
+        }
     }
 
-    #[derive(Clone, Debug, Deserialize)]
+    #[derive(Clone, Debug, Deserialize, PartialEq)]
     pub struct Server {
         host: String,
         port: u16,
         #[serde(default)]
         enable_query: bool,
+        // `DISABLE_QUERY_WARNING` environment variable take precedence
+        #[serde(default)]
+        suppress_query_warning: bool,
+        // Return cloudflare error code and message to client, may leak zone information
+        #[serde(default)]
+        expose_api_error: bool,
+        #[serde(default)]
+        allow_delete: bool,
+        #[serde(default)]
+        allow_batch: bool,
+        // Batch entries processed concurrently, still limited by `max_concurrent_cf_requests`
+        batch_concurrency: Option<usize>,
+        // Requests per second across all clients, excess requests get 429. Disabled if not set
+        global_rate_limit: Option<u32>,
+        // Default log filter, `RUST_LOG` and `--log-level` take precedence
+        log_level: Option<String>,
+        // Response of unmatched routes, default is 403
+        fallback_status: Option<u16>,
+        fallback_body: Option<String>,
+        max_body_bytes: Option<usize>,
+        // Return 403 instead of 404 for unknown client
+        #[serde(default)]
+        opaque_unknown_client: bool,
+        // `uuid` (default) or `free`
+        #[serde(default)]
+        identifier_mode: IdentifierMode,
+        // Accept private/reserved address for split-horizon setups
+        #[serde(default)]
+        allow_private_ips: bool,
+        // Return current records on GET without IP
+        #[serde(default)]
+        allow_lookup: bool,
+        // Show crate version in root route, default is true
+        expose_version: Option<bool>,
+        // Bearer token for admin endpoints, admin endpoints always return 403 if not set
+        admin_token: Option<String>,
+        // Max time to wait for relay queue draining on shutdown
+        shutdown_grace_secs: Option<u64>,
+        // Timeout of whole update handler, return 504 if exceeded
+        request_timeout_secs: Option<u64>,
+        // Signal to force exit during graceful shutdown: `ctrl-c` (default, second Control-C),
+        // `sigterm` or `disabled`
+        #[serde(default)]
+        force_exit: ForceExit,
+        // Listen backlog and keepalive of accepted sockets, system default if not set
+        tcp_backlog: Option<i32>,
+        tcp_keepalive_secs: Option<u64>,
+        // Accept HTTP/2 over cleartext (h2c) besides HTTP/1.1
+        accept_h2c: Option<bool>,
+        // Extra headers redacted in query route, in addition to authorization, cookie, ...
+        #[serde(default)]
+        redact_headers: Vec<String>,
+        // Origins allowed to call update routes from browser, `*` allows any origin.
+        // CORS is disabled if empty
+        #[serde(default)]
+        cors_allowed_origins: Vec<String>,
+    }
+
+    #[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+    #[serde(rename_all = "kebab-case")]
+    pub enum ForceExit {
+        #[default]
+        CtrlC,
+        #[serde(rename = "sigterm")]
+        SigTerm,
+        Disabled,
     }
 
     impl Server {
         pub fn enable_query(&self) -> bool {
             self.enable_query
         }
+        pub fn suppress_query_warning(&self) -> bool {
+            self.suppress_query_warning
+        }
+        pub fn expose_api_error(&self) -> bool {
+            self.expose_api_error
+        }
+        pub fn allow_delete(&self) -> bool {
+            self.allow_delete
+        }
+        pub fn allow_batch(&self) -> bool {
+            self.allow_batch
+        }
+        pub fn max_body_bytes(&self) -> usize {
+            self.max_body_bytes.unwrap_or(DEFAULT_MAX_BODY_BYTES)
+        }
+        pub fn opaque_unknown_client(&self) -> bool {
+            self.opaque_unknown_client
+        }
+        pub fn identifier_mode(&self) -> IdentifierMode {
+            self.identifier_mode
+        }
+        pub fn allow_private_ips(&self) -> bool {
+            self.allow_private_ips
+        }
+        pub fn allow_lookup(&self) -> bool {
+            self.allow_lookup
+        }
+        pub fn expose_version(&self) -> bool {
+            self.expose_version.unwrap_or(true)
+        }
+        pub fn admin_token(&self) -> &Option<String> {
+            &self.admin_token
+        }
+        pub fn redact_headers(&self) -> &Vec<String> {
+            &self.redact_headers
+        }
+        pub fn global_rate_limit(&self) -> Option<u32> {
+            self.global_rate_limit.filter(|rate| *rate > 0)
+        }
+        // Checked while loading configure
+        pub fn log_level(&self) -> Option<LevelFilter> {
+            self.log_level
+                .as_deref()
+                .and_then(|level| level.parse().ok())
+        }
+        pub fn batch_concurrency(&self) -> usize {
+            self.batch_concurrency
+                .unwrap_or(DEFAULT_BATCH_CONCURRENCY)
+                .max(1)
+        }
+        pub fn cors_allowed_origins(&self) -> &Vec<String> {
+            &self.cors_allowed_origins
+        }
+        pub fn request_timeout_secs(&self) -> Option<u64> {
+            self.request_timeout_secs
+        }
+        pub fn force_exit(&self) -> ForceExit {
+            self.force_exit
+        }
+        pub fn tcp_backlog(&self) -> Option<i32> {
+            self.tcp_backlog
+        }
+        pub fn accept_h2c(&self) -> bool {
+            self.accept_h2c.unwrap_or(true)
+        }
+        pub fn tcp_keepalive(&self) -> Option<Duration> {
+            self.tcp_keepalive_secs
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs)
+        }
+        pub fn shutdown_grace(&self) -> Duration {
+            Duration::from_secs(
+                self.shutdown_grace_secs
+                    .unwrap_or(DEFAULT_SHUTDOWN_GRACE_SECS),
+            )
+        }
+        pub fn fallback(&self) -> (StatusCode, String) {
+            let status = self
+                .fallback_status
+                .and_then(|status| StatusCode::from_u16(status).ok())
+                .unwrap_or(StatusCode::FORBIDDEN);
+            let body = self.fallback_body.clone().unwrap_or_else(|| {
+                format!(
+                    "{} {}",
+                    status.as_u16(),
+                    status.canonical_reason().unwrap_or_default()
+                )
+            });
+            (status, body)
+        }
+    }
+
+    // Absolute paths are kept untouched
+    fn resolve_path(base: &std::path::Path, path: &mut Option<String>) {
+        if let Some(p) = path.as_mut() {
+            if std::path::Path::new(p.as_str()).is_relative() {
+                *p = base.join(p.as_str()).to_string_lossy().to_string();
+            }
+        }
+    }
+
+    // IPv6 address should be wrapped in brackets
+    fn format_bind(f: &mut Formatter<'_>, host: &str, port: u16) -> std::fmt::Result {
+        if host.contains(':') && !host.starts_with('[') {
+            write!(f, "[{}]:{}", host, port)
+        } else {
+            write!(f, "{}:{}", host, port)
+        }
     }
 
     impl std::fmt::Display for Server {
         fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-            write!(f, "{}:{}", self.host, self.port)
+            format_bind(f, &self.host, self.port)
+        }
+    }
+
+    #[derive(Clone, Debug, Deserialize, PartialEq)]
+    pub struct AdminServer {
+        host: String,
+        port: u16,
+    }
+
+    impl std::fmt::Display for AdminServer {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            format_bind(f, &self.host, self.port)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{resolve_path, Config, Server};
+
+        fn bind(host: &str) -> String {
+            toml::from_str::<Server>(&format!("host = {:?}\nport = 21336", host))
+                .unwrap()
+                .to_string()
+        }
+
+        #[test]
+        fn test_server_display() {
+            assert_eq!(bind("127.0.0.1"), "127.0.0.1:21336");
+            assert_eq!(bind("::1"), "[::1]:21336");
+            assert_eq!(bind("[::1]"), "[::1]:21336");
+            assert_eq!(bind("localhost"), "localhost:21336");
+        }
+
+        #[test]
+        fn test_resolve_path() {
+            let base = std::path::Path::new("/etc/waffle");
+            let mut path = Some("token".to_string());
+            resolve_path(base, &mut path);
+            assert_eq!(path.as_deref(), Some("/etc/waffle/token"));
+            let mut path = Some("/var/lib/state.json".to_string());
+            resolve_path(base, &mut path);
+            assert_eq!(path.as_deref(), Some("/var/lib/state.json"));
+            let mut path = None;
+            resolve_path(base, &mut path);
+            assert_eq!(path, None);
+        }
+
+        #[tokio::test]
+        async fn test_token_file_relative_to_config() {
+            let dir = std::env::temp_dir().join(format!("waffle-test-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("token"), "secret\n").unwrap();
+            std::fs::write(
+                dir.join("config.toml"),
+                std::fs::read_to_string("config.toml.default")
+                    .unwrap()
+                    .replacen("token = ", "token_file = \"token\"\n#token = ", 1),
+            )
+            .unwrap();
+            let config = Config::try_from_file(dir.join("config.toml").to_str().unwrap()).await;
+            std::fs::remove_dir_all(&dir).unwrap();
+            assert_eq!(config.unwrap().token(), "secret");
         }
     }
 }
@@ -175,8 +1200,10 @@ mod config {
 mod web {
     use serde_derive::{Deserialize, Serialize};
 
+    // Field name of common clients are accepted, forwarded body always use `ip`
     #[derive(Clone, Debug, Deserialize, Serialize)]
     pub struct PostData {
+        #[serde(alias = "address", alias = "ip_address")]
         ip: String,
     }
 
@@ -188,14 +1215,30 @@ mod web {
             Self { ip }
         }
     }
+
+    #[derive(Clone, Debug, Deserialize)]
+    pub struct BatchEntry {
+        uuid: String,
+        #[serde(alias = "address", alias = "ip_address")]
+        ip: String,
+    }
+
+    impl BatchEntry {
+        pub fn uuid(&self) -> &str {
+            &self.uuid
+        }
+        pub fn ip(&self) -> &str {
+            &self.ip
+        }
+    }
 }
 
 mod relay {
-    use super::RelayConfig;
-    use anyhow::anyhow;
+    use super::{MissingHeader, RelayConfig};
     use log::warn;
     use serde_derive::Deserialize;
     use std::collections::HashMap;
+    use std::time::Duration;
 
     const DISABLE_URL_WARNING: &str = "DISABLE_URL_WARNING";
 
@@ -204,6 +1247,12 @@ mod relay {
         enabled: bool,
         target: Vec<String>,
         clients: HashMap<String, String>,
+        queue: bool,
+        queue_capacity: usize,
+        queue_ttl: Duration,
+        missing_header: MissingHeader,
+        broadcast: bool,
+        target_timeout: Duration,
     }
 
     impl Relay {
@@ -211,6 +1260,30 @@ mod relay {
             self.enabled
         }
 
+        pub fn queue(&self) -> bool {
+            self.queue
+        }
+
+        pub fn queue_capacity(&self) -> usize {
+            self.queue_capacity
+        }
+
+        pub fn queue_ttl(&self) -> Duration {
+            self.queue_ttl
+        }
+
+        pub fn missing_header(&self) -> MissingHeader {
+            self.missing_header
+        }
+
+        pub fn broadcast(&self) -> bool {
+            self.broadcast
+        }
+
+        pub fn target_timeout(&self) -> Duration {
+            self.target_timeout
+        }
+
         pub fn target(&self) -> &Vec<String> {
             &self.target
         }
@@ -220,24 +1293,18 @@ mod relay {
         }
     }
 
-    impl TryFrom<RelayConfig> for Relay {
-        type Error = anyhow::Error;
-
-        fn try_from(value: RelayConfig) -> Result<Self, Self::Error> {
+    // Configure is validated while loading (e.g. clients is not empty)
+    impl From<RelayConfig> for Relay {
+        fn from(value: RelayConfig) -> Self {
             if !value.enabled() {
-                return Ok(Default::default());
+                return Default::default();
             }
             let targets = value.target();
 
-            // Check clients is empty
-            if value.clients().is_empty() {
-                return Err(anyhow!("Clients is empty."));
-            }
-
             // Check if disable warning
             let disable_warning = std::env::var(DISABLE_URL_WARNING)
                 .map(|s| s.parse::<i64>().unwrap_or_default() != 0)
-                .unwrap_or_default();
+                .unwrap_or(value.suppress_url_warning());
 
             // Variable to store is warning has sent already
             let mut warning_sent = false;
@@ -251,7 +1318,7 @@ mod relay {
                 }
                 if warning_sent {
                     warn!(
-                        "You can disable this warning by set `{}` environment variable to `1`",
+                        "You can disable this warning by set `{}` environment variable to `1` or relay.suppress_url_warning",
                         DISABLE_URL_WARNING
                     );
                 }
@@ -263,16 +1330,25 @@ mod relay {
                 m.insert(client.uuid().to_string(), client.target().to_string());
             }
 
-            Ok(Self {
+            Self {
                 enabled: true,
                 target: value.target(),
                 clients: m,
-            })
+                queue: value.queue(),
+                queue_capacity: value.queue_capacity(),
+                queue_ttl: value.queue_ttl(),
+                missing_header: value.missing_header(),
+                broadcast: value.broadcast(),
+                target_timeout: value.target_timeout(),
+            }
         }
     }
 }
 
 pub use config::ZoneMapper;
-pub use config::{Config, Relay as RelayConfig};
+pub use config::{
+    Config, ConfigError, ForceExit, IdentifierMode, MissingHeader, OwnershipCheck, Provider,
+    Relay as RelayConfig,
+};
 pub use relay::Relay;
-pub use web::PostData;
+pub use web::{BatchEntry, PostData};
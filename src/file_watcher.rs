@@ -2,10 +2,10 @@ mod v1 {
     use crate::cloudflare::ApiRequest;
     use crate::datastructures::Config;
     use log::{debug, error, info, warn};
-    use notify::{Event, RecursiveMode, Watcher};
+    use notify::{Event, PollWatcher, RecursiveMode, Watcher};
     use std::path::PathBuf;
-    use std::sync::atomic::{AtomicBool, Ordering};
-    use std::sync::Arc;
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
     use std::thread::JoinHandle;
     use std::time::Duration;
     use tap::TapFallible;
@@ -15,74 +15,123 @@ mod v1 {
     struct DataToUpdate {
         path: String,
         data: Arc<RwLock<ApiRequest>>,
-        relay_flag: Arc<AtomicBool>,
+        // Last applied configure, used to skip reload without effective change
+        last_config: Mutex<Option<Config>>,
     }
 
     impl DataToUpdate {
-        pub fn new(
-            path: String,
-            data: Arc<RwLock<ApiRequest>>,
-            relay_flag: Arc<AtomicBool>,
-        ) -> Self {
+        pub fn new(path: String, data: Arc<RwLock<ApiRequest>>) -> Self {
             Self {
                 path,
                 data,
-                relay_flag,
+                last_config: Default::default(),
             }
         }
 
+        // Files referenced by last applied configure (e.g. relay clients file)
+        pub fn extra_files(&self) -> Vec<PathBuf> {
+            self.last_config
+                .lock()
+                .unwrap()
+                .as_ref()
+                .and_then(|config| config.relay_clients_file().clone())
+                .into_iter()
+                .map(PathBuf::from)
+                .collect()
+        }
+
+        pub async fn load_current(&self) {
+            *self.last_config.lock().unwrap() = Config::try_from_file(&self.path).await.ok();
+        }
+
         pub async fn update(&self) -> Option<()> {
+            // Operator should notice the edit does not take effect
             let config = Config::try_from_file(&self.path)
                 .await
-                .tap_err(|e| error!("[Can be safely ignored] Unable to parse new file: {:?}", e))
+                .map_err(anyhow::Error::from)
+                .tap_err(|e| {
+                    warn!(
+                        "Reload configure file {:?} failed, previous configure is kept: {:#}",
+                        self.path, e
+                    )
+                })
                 .ok()?;
 
-            let mut data = self.data.write().await;
-            let relay = data.is_relay();
-            let new_data = ApiRequest::try_from(config)
+            if self.last_config.lock().unwrap().as_ref() == Some(&config) {
+                debug!("Configure file is not changed, skip reload");
+                return Some(());
+            }
+            let last_config = config.clone();
+
+            let mut new_data = ApiRequest::try_from(config)
                 .tap_err(|e| {
-                    error!(
-                        "[Can be safely ignored] Unable parse configure to inner type {:?}",
-                        e
+                    warn!(
+                        "Reload configure file {:?} failed, previous configure is kept: {}",
+                        self.path, e
                     )
                 })
                 .ok()?;
-            if !relay && new_data.is_relay() {
+
+            // Handlers hold one read guard for whole request, so swap is atomic to them
+            let mut data = self.data.write().await;
+            if !data.is_relay() && new_data.is_relay() {
                 debug!("Server is running on relay mode");
             }
+            new_data.inherit_state(&data);
             *data = new_data;
-            self.relay_flag.store(relay, Ordering::Relaxed);
+            *self.last_config.lock().unwrap() = Some(last_config);
             info!("Reload configure file successful, {}", data.info());
             Some(())
         }
     }
 
+    // Poll mode is used on filesystems without inotify support (NFS, SMB, ...)
+    #[derive(Clone, Copy, Debug)]
+    pub enum WatcherMode {
+        Native,
+        Poll(Duration),
+    }
+
+    #[derive(Debug)]
+    enum WatcherCommand {
+        // Extra files referenced by reloaded configure
+        Rewatch(Vec<PathBuf>),
+        Stop,
+    }
+
     #[derive(Debug)]
     pub struct FileWatchDog {
         handler: JoinHandle<Option<()>>,
-        stop_signal_channel: oneshot::Sender<bool>,
+        command_channel: mpsc::Sender<WatcherCommand>,
     }
 
     impl FileWatchDog {
-        pub fn file_watching(
+        fn file_watching(
             file: String,
-            stop_signal_channel: oneshot::Receiver<bool>,
+            command_channel: (mpsc::Sender<WatcherCommand>, mpsc::Receiver<WatcherCommand>),
             data: Arc<RwLock<ApiRequest>>,
-            relay_flag: Arc<AtomicBool>,
+            mode: WatcherMode,
+            extra_files: Vec<String>,
         ) -> Option<()> {
             let path = PathBuf::from(file.clone());
 
-            let data = DataToUpdate::new(file, data, relay_flag);
+            let data = DataToUpdate::new(file, data);
+
+            // Runtime is moved into watcher callback, it will be dropped with watcher
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .build()
+                .tap_err(|e| error!("[Can be safely ignored] Unable create runtime: {:?}", e))
+                .ok()?;
+
+            runtime.block_on(data.load_current());
 
-            let mut watcher = notify::recommended_watcher(move |res| match res {
+            // Watcher can't be changed inside its own callback, new extra files are sent back
+            let (command_sender, command_receiver) = command_channel;
+            let handler = move |res: notify::Result<Event>| match res {
                 Ok(event) => {
-                    if Self::decide(event) {
-                        tokio::runtime::Builder::new_current_thread()
-                            .build()
-                            .map(|runtime| runtime.block_on(data.update()))
-                            .tap_err(|e| {
-                                error!("[Can be safely ignored] Unable create runtime: {:?}", e)
-                            })
+                    if Self::decide(event, mode) && runtime.block_on(data.update()).is_some() {
+                        command_sender
+                            .send(WatcherCommand::Rewatch(data.extra_files()))
                             .ok();
                     }
                 }
@@ -92,7 +141,25 @@ mod v1 {
                         e
                     )
                 }
-            })
+            };
+
+            let mut watcher: Box<dyn Watcher> = match mode {
+                WatcherMode::Native => {
+                    info!("File watcher is running in native mode");
+                    notify::recommended_watcher(handler).map(|w| Box::new(w) as Box<dyn Watcher>)
+                }
+                WatcherMode::Poll(interval) => {
+                    info!(
+                        "File watcher is running in poll mode, interval {:?}",
+                        interval
+                    );
+                    PollWatcher::new(
+                        handler,
+                        notify::Config::default().with_poll_interval(interval),
+                    )
+                    .map(|w| Box::new(w) as Box<dyn Watcher>)
+                }
+            }
             .tap_err(|e| error!("[Can be safely ignored] Can't start watcher {:?}", e))
             .ok()?;
 
@@ -101,26 +168,74 @@ mod v1 {
                 .tap_err(|e| error!("[Can be safely ignored] Unable to watch file: {:?}", e))
                 .ok()?;
 
-            stop_signal_channel
-                .recv()
-                .tap_err(|e| {
-                    error!(
-                        "[Can be safely ignored] Got error while poll oneshot event: {:?}",
-                        e
-                    )
-                })
-                .ok();
+            // Change of extra file (e.g. relay clients file) reloads main configure
+            let mut extra_files = Self::rewatch(
+                watcher.as_mut(),
+                Vec::new(),
+                extra_files.into_iter().map(PathBuf::from).collect(),
+            );
+
+            loop {
+                match command_receiver.recv() {
+                    Ok(WatcherCommand::Rewatch(files)) => {
+                        extra_files = Self::rewatch(watcher.as_mut(), extra_files, files);
+                    }
+                    Ok(WatcherCommand::Stop) => break,
+                    Err(e) => {
+                        error!(
+                            "[Can be safely ignored] Got error while poll watcher command: {:?}",
+                            e
+                        );
+                        break;
+                    }
+                }
+            }
+
+            Self::rewatch(watcher.as_mut(), extra_files, Vec::new());
 
             watcher
                 .unwatch(&path)
                 .tap_err(|e| error!("[Can be safely ignored] Unable to unwatch file: {:?}", e))
                 .ok()?;
 
+            drop(watcher);
+
             debug!("File watcher exited!");
             Some(())
         }
 
-        fn decide(event: Event) -> bool {
+        // Unwatch files no longer referenced and watch new ones, return files being watched
+        fn rewatch(
+            watcher: &mut dyn Watcher,
+            watching: Vec<PathBuf>,
+            files: Vec<PathBuf>,
+        ) -> Vec<PathBuf> {
+            for extra in watching.iter().filter(|extra| !files.contains(extra)) {
+                debug!("Unwatch file {:?}", extra);
+                watcher.unwatch(extra).ok();
+            }
+            files
+                .into_iter()
+                .filter(|extra| {
+                    watching.contains(extra)
+                        || watcher
+                            .watch(extra, RecursiveMode::NonRecursive)
+                            .tap_err(|e| {
+                                error!(
+                                    "[Can be safely ignored] Unable to watch file {:?}: {:?}",
+                                    extra, e
+                                )
+                            })
+                            .is_ok()
+                })
+                .collect()
+        }
+
+        fn decide(event: Event, mode: WatcherMode) -> bool {
+            // Poll watcher only reports modification
+            if let (WatcherMode::Poll(_), notify::EventKind::Modify(_)) = (mode, event.kind) {
+                return true;
+            }
             if let notify::EventKind::Access(notify::event::AccessKind::Close(
                 notify::event::AccessMode::Write,
             )) = event.kind
@@ -133,21 +248,23 @@ mod v1 {
         pub fn start(
             path: String,
             data: Arc<RwLock<ApiRequest>>,
-            relay_flag: Arc<AtomicBool>,
+            mode: WatcherMode,
+            extra_files: Vec<String>,
         ) -> Self {
-            let (stop_signal_channel, receiver) = oneshot::channel();
+            let (command_channel, receiver) = mpsc::channel();
+            let sender = command_channel.clone();
             Self {
-                handler: std::thread::spawn(|| {
-                    Self::file_watching(path, receiver, data, relay_flag)
+                handler: std::thread::spawn(move || {
+                    Self::file_watching(path, (sender, receiver), data, mode, extra_files)
                 }),
-                stop_signal_channel,
+                command_channel,
             }
         }
 
         pub fn stop(self) -> Option<()> {
             if !self.handler.is_finished() {
-                self.stop_signal_channel
-                    .send(true)
+                self.command_channel
+                    .send(WatcherCommand::Stop)
                     .tap_err(|e| {
                         error!(
                 "[Can be safely ignored] Unable send terminate signal to file watcher thread: {:?}",
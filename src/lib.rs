@@ -0,0 +1,498 @@
+use anyhow::anyhow;
+use axum::extract::DefaultBodyLimit;
+use axum::http::{header, HeaderName, HeaderValue, Method, StatusCode};
+use axum::{Json, Router};
+use axum_server::{AddrIncomingConfig, HttpConfig};
+use log::{debug, error, info, warn};
+use serde_json::json;
+use std::hint::unreachable_unchecked;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tap::TapFallible;
+use tokio::sync::{oneshot, RwLock};
+use tower::ServiceBuilder;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tower_http::trace::TraceLayer;
+
+use crate::file_watcher::{FileWatchDog, WatcherMode};
+use crate::web::{
+    admin_config, batch, delete, get, get_debug, global_rate_limit, post, RateLimiter,
+};
+
+mod cloudflare;
+mod datastructures;
+mod file_watcher;
+mod provider;
+mod web;
+
+pub use cloudflare::{ApiError, ApiRequest, UpdateResult, UpdateStatus};
+pub use datastructures::{Config, ConfigError, ForceExit, RelayConfig};
+
+// Options not covered by configure file, mostly from command line
+#[derive(Debug, Default)]
+pub struct RunOptions {
+    // Configure file to watch, reload is disabled if not set
+    pub config_location: Option<String>,
+    pub query_enabled: bool,
+    pub verify_token: bool,
+    // "native" or "poll", override watcher_mode in configure file
+    pub watcher_mode: Option<String>,
+    // Probe relay targets on startup, strict check refuses to start if none is reachable
+    pub check_relay: bool,
+    pub strict_relay_check: bool,
+    // Receive actual bound address of server, e.g. port 0 is used
+    pub bound_addr: Option<oneshot::Sender<SocketAddr>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct RouterOptions {
+    pub allow_delete: bool,
+    pub allow_batch: bool,
+    pub query_enabled: bool,
+    pub expose_version: bool,
+    // Serve admin routes with separate router
+    pub separate_admin: bool,
+    pub max_body_bytes: usize,
+    pub max_batch_body_bytes: usize,
+    pub fallback: (StatusCode, String),
+    pub cors_allowed_origins: Vec<String>,
+    // Requests per second of main router, shared by all clients
+    pub global_rate_limit: Option<u32>,
+}
+
+impl RouterOptions {
+    pub fn from_config(config: &Config, query_enabled: bool) -> Self {
+        Self {
+            allow_delete: config.allow_delete(),
+            allow_batch: config.allow_batch(),
+            query_enabled,
+            expose_version: config.expose_version(),
+            separate_admin: config.get_admin_bind().is_some(),
+            max_body_bytes: config.max_body_bytes(),
+            max_batch_body_bytes: config.max_batch_body_bytes(),
+            fallback: config.fallback(),
+            cors_allowed_origins: config.cors_allowed_origins().clone(),
+            global_rate_limit: config.global_rate_limit(),
+        }
+    }
+}
+
+// Preflight of update routes is answered by this layer, None if no origin is allowed
+fn cors_layer(options: &RouterOptions) -> Option<CorsLayer> {
+    if options.cors_allowed_origins.is_empty() {
+        return None;
+    }
+    let origin = if options.cors_allowed_origins.iter().any(|o| o.eq("*")) {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(
+            options
+                .cors_allowed_origins
+                .iter()
+                .filter_map(|origin| HeaderValue::from_str(origin).ok()),
+        )
+    };
+    let mut methods = vec![Method::GET, Method::POST, Method::PATCH];
+    if options.allow_delete {
+        methods.push(Method::DELETE);
+    }
+    Some(
+        CorsLayer::new()
+            .allow_origin(origin)
+            .allow_methods(methods)
+            .allow_headers([
+                header::ACCEPT,
+                header::CONTENT_TYPE,
+                HeaderName::from_static("x-timestamp"),
+                HeaderName::from_static("x-signature"),
+                HeaderName::from_static("x-request-id"),
+            ]),
+    )
+}
+
+// Return main router and admin router, admin routes are merged into main router if not separated
+pub fn build_router(
+    options: &RouterOptions,
+    request: Arc<RwLock<ApiRequest>>,
+) -> (Router, Option<Router>) {
+    // PATCH is alias of POST for clients restricted to specific verbs
+    let sub_route = axum::routing::get(get).post(post).patch(post);
+    let sub_route = if options.allow_delete {
+        sub_route.delete(delete)
+    } else {
+        sub_route
+    }
+    .layer(DefaultBodyLimit::max(options.max_body_bytes));
+    let cors = cors_layer(options);
+    let sub_route = match cors {
+        Some(ref cors) => sub_route.layer(cors.clone()),
+        None => sub_route,
+    };
+
+    // Admin and debug routes, served on separate port if admin server is configured
+    let admin_router = Router::new().route("/admin/config", axum::routing::get(admin_config));
+    let admin_router = if options.query_enabled {
+        admin_router.route("/query", axum::routing::get(get_debug))
+    } else {
+        admin_router
+    };
+
+    let router = Router::new().route("/:sub_id", sub_route);
+    let router = if options.allow_batch {
+        let batch_route =
+            axum::routing::post(batch).layer(DefaultBodyLimit::max(options.max_batch_body_bytes));
+        router.route(
+            "/batch",
+            match cors {
+                Some(cors) => batch_route.layer(cors),
+                None => batch_route,
+            },
+        )
+    } else {
+        router
+    };
+
+    let (router, admin_router) = if options.separate_admin {
+        (router, Some(admin_router))
+    } else {
+        (router.merge(admin_router), None)
+    };
+    let expose_version = options.expose_version;
+
+    let router = router
+        .route(
+            "/",
+            axum::routing::get(move || async move {
+                if expose_version {
+                    Json(json!({ "version": env!("CARGO_PKG_VERSION"), "status": 200 }))
+                } else {
+                    Json(json!({ "status": 200 }))
+                }
+            }),
+        )
+        .fallback({
+            let fallback = options.fallback.clone();
+            || async { fallback }
+        })
+        .with_state(request.clone());
+    let router = match options.global_rate_limit {
+        Some(rate) => router.layer(axum::middleware::from_fn_with_state(
+            Arc::new(RateLimiter::new(rate)),
+            global_rate_limit,
+        )),
+        None => router,
+    }
+    .layer(ServiceBuilder::new().layer(TraceLayer::new_for_http()));
+
+    let admin_router = admin_router.map(|admin_router| {
+        let fallback = options.fallback.clone();
+        admin_router
+            .fallback(|| async { fallback })
+            .with_state(request)
+            .layer(ServiceBuilder::new().layer(TraceLayer::new_for_http()))
+    });
+
+    (router, admin_router)
+}
+
+async fn wait_force_exit(force_exit: ForceExit) {
+    match force_exit {
+        ForceExit::CtrlC => tokio::signal::ctrl_c().await.unwrap(),
+        #[cfg(unix)]
+        ForceExit::SigTerm => {
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .unwrap()
+                .recv()
+                .await;
+        }
+        _ => std::future::pending().await,
+    }
+}
+
+// Custom backlog is capped by system limit (e.g. `net.core.somaxconn` on Linux)
+fn bind_server(addr: SocketAddr, backlog: Option<i32>) -> anyhow::Result<axum_server::Server> {
+    let Some(backlog) = backlog else {
+        return Ok(axum_server::bind(addr));
+    };
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::STREAM,
+        Some(socket2::Protocol::TCP),
+    )?;
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket
+        .bind(&addr.into())
+        .map_err(|e| anyhow!("Unable bind to {}: {}", addr, e))?;
+    socket.listen(backlog)?;
+    Ok(axum_server::from_tcp(socket.into()))
+}
+
+fn parse_bind(bind: &str) -> anyhow::Result<SocketAddr> {
+    bind.parse().map_err(|e| {
+        anyhow!(
+            "Unable parse bind address {:?}: {}, should be `host:port` like `127.0.0.1:21336`, IPv6 host should be wrapped in brackets like `[::1]:21336`",
+            bind,
+            e
+        )
+    })
+}
+
+// Run server with loaded configure until Control-C is received
+pub async fn run(config: Config, opts: RunOptions) -> anyhow::Result<()> {
+    let bind = config.get_bind();
+    info!("Version: {}", env!("CARGO_PKG_VERSION"));
+    debug!("Server bind to {}", &bind);
+    let bind_addr = parse_bind(&bind)?;
+    let admin_bind_addr = config
+        .get_admin_bind()
+        .map(|bind| {
+            debug!("Admin server bind to {}", &bind);
+            parse_bind(&bind)
+        })
+        .transpose()?;
+
+    let query_enabled = opts.query_enabled || config.enable_query();
+    let suppress_query_warning = config.suppress_query_warning();
+    let shutdown_grace = config.shutdown_grace();
+    let force_exit = config.force_exit();
+    let tcp_backlog = config.tcp_backlog();
+    let tcp_keepalive = config.tcp_keepalive();
+    let accept_h2c = config.accept_h2c();
+    let reconcile_interval = config.reconcile_interval();
+    let watch_extra_files = config
+        .relay_clients_file()
+        .iter()
+        .cloned()
+        .collect::<Vec<_>>();
+    // Command line option take precedence
+    let watcher_mode = match opts.watcher_mode.as_deref().or(config.watcher_mode()) {
+        Some("poll") => WatcherMode::Poll(config.watcher_poll_interval()),
+        _ => WatcherMode::Native,
+    };
+    let verify_token = opts.verify_token || config.verify_token();
+    if let Some(local_address) = config.local_address() {
+        info!("Outbound requests bind to {}", local_address);
+    }
+
+    let routes = RouterOptions::from_config(&config, query_enabled);
+    let request = ApiRequest::try_from(config)?;
+    info!("Loaded configure, {}", request.info());
+
+    if request.is_relay() {
+        debug!("Server is running on relay mode");
+        if opts.check_relay || opts.strict_relay_check {
+            let reachable = request.check_relay_targets().await;
+            if reachable == 0 && opts.strict_relay_check {
+                return Err(anyhow!("None of relay targets is reachable"));
+            }
+        }
+    } else if verify_token {
+        request.verify_token().await?;
+    }
+
+    let request = Arc::new(RwLock::new(request));
+
+    if query_enabled
+        && !std::env::var("DISABLE_QUERY_WARNING")
+            .map(|v| v.eq("1"))
+            .unwrap_or(suppress_query_warning)
+    {
+        warn!("Route query is enabled, it may cause some security issue. Set DISABLE_QUERY_WARNING=1 or server.suppress_query_warning to disable this warning.");
+    }
+
+    let (router, admin_router) = build_router(&routes, request.clone());
+    let admin_router = admin_bind_addr.zip(admin_router);
+
+    // Retry queued relay forwards in background
+    let relay_queue_task = tokio::spawn({
+        let request = request.clone();
+        async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                // Lock is not held across network I/O, which would block configure reload
+                // and then every incoming request
+                let forwarder = {
+                    let request = request.read().await;
+                    if request.relay_queue_len() == 0 {
+                        continue;
+                    }
+                    request.relay_forwarder()
+                };
+                forwarder.retry_relay_queue().await;
+            }
+        }
+    });
+
+    // Correct out-of-band edit of records periodically
+    let reconcile_task = reconcile_interval.map(|reconcile_interval| {
+        let request = request.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(reconcile_interval);
+            // First tick completes immediately, nothing is pushed yet
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                // Same as relay queue, lock is released before any cloudflare request
+                let snapshot = request.read().await.clone();
+                snapshot.reconcile().await;
+            }
+        })
+    });
+
+    let server_handler = axum_server::Handle::new();
+    let server = bind_server(bind_addr, tcp_backlog)?;
+    // Keepalive is applied on accepted sockets, ignored on platform without support
+    let server = match tcp_keepalive {
+        Some(keepalive) => server.addr_incoming_config(
+            AddrIncomingConfig::new()
+                .tcp_keepalive(Some(keepalive))
+                .build(),
+        ),
+        None => server,
+    };
+    let server = if accept_h2c {
+        server
+    } else {
+        server.http_config(HttpConfig::new().http1_only(true).build())
+    };
+    let server = tokio::spawn(
+        server
+            .handle(server_handler.clone())
+            .serve(router.into_make_service_with_connect_info::<SocketAddr>()),
+    );
+    if let Some(bound_addr) = opts.bound_addr {
+        let server_handler = server_handler.clone();
+        tokio::spawn(async move {
+            // None if server failed to bind, error is returned by server task
+            if let Some(addr) = server_handler.listening().await {
+                info!("Server listening on {}", addr);
+                bound_addr.send(addr).ok();
+            }
+        });
+    }
+
+    let admin_server_handler = axum_server::Handle::new();
+    // Bind eagerly, so failure of admin server is reported on startup
+    let admin_server = admin_router
+        .map(|(addr, admin_router)| {
+            let listener = std::net::TcpListener::bind(addr)
+                .map_err(|e| anyhow!("Unable bind admin server to {}: {}", addr, e))?;
+            listener.set_nonblocking(true)?;
+            Ok::<_, anyhow::Error>(tokio::spawn(
+                axum_server::from_tcp(listener)
+                    .handle(admin_server_handler.clone())
+                    .serve(admin_router.into_make_service_with_connect_info::<SocketAddr>()),
+            ))
+        })
+        .transpose()?;
+
+    let file_watcher_handler = opts.config_location.map(|config_location| {
+        FileWatchDog::start(
+            config_location,
+            request.clone(),
+            watcher_mode,
+            watch_extra_files,
+        )
+    });
+
+    let server_ret = tokio::select! {
+        _ = async {
+            tokio::signal::ctrl_c().await.unwrap();
+            info!("Recv Control-C send graceful shutdown command.");
+            // Graceful shutdown is bounded if second Control-C can't force exit
+            let graceful_timeout = match force_exit {
+                ForceExit::CtrlC => None,
+                _ => Some(shutdown_grace),
+            };
+            server_handler.graceful_shutdown(graceful_timeout);
+            admin_server_handler.graceful_shutdown(graceful_timeout);
+            wait_force_exit(force_exit).await;
+            warn!("Force to exit!");
+            std::process::exit(137)
+        } => {
+            unsafe { unreachable_unchecked() }
+        },
+        ret = async {
+            let ret = server.await;
+            // Wait queued relay forwards, second Control-C still force exit
+            let drain = tokio::time::timeout(shutdown_grace, async {
+                while request.read().await.relay_queue_len() > 0 {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            });
+            if drain.await.is_err() {
+                warn!(
+                    "Shutdown grace timeout elapsed, {} queued relay forwards dropped",
+                    request.read().await.relay_queue_len()
+                );
+            }
+            ret
+        } => ret,
+    };
+
+    // Results are collected without `?`, so cleanup below always runs
+    let admin_ret = match admin_server {
+        Some(admin_server) => {
+            admin_server_handler.graceful_shutdown(None);
+            Some(admin_server.await)
+        }
+        None => None,
+    };
+
+    relay_queue_task.abort();
+    request.read().await.save_state().await;
+    if let Some(reconcile_task) = reconcile_task {
+        reconcile_task.abort();
+    }
+
+    if let Some(file_watcher_handler) = file_watcher_handler {
+        tokio::task::spawn_blocking(|| file_watcher_handler.stop())
+            .await
+            .tap_err(|e| {
+                error!(
+                    "[Can be safely ignored] Unable to spawn stop file watcher thread {:?}",
+                    e
+                )
+            })
+            .ok();
+    }
+
+    server_ret??;
+    if let Some(admin_ret) = admin_ret {
+        admin_ret??;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_bind;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_parse_bind() {
+        assert_eq!(
+            parse_bind("127.0.0.1:21336").unwrap(),
+            "127.0.0.1:21336".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(
+            parse_bind("[::1]:21336").unwrap(),
+            SocketAddr::from((std::net::Ipv6Addr::LOCALHOST, 21336))
+        );
+    }
+
+    #[test]
+    fn test_parse_bind_error() {
+        // Hostname is not resolved
+        assert!(parse_bind("localhost:21336").is_err());
+        for bind in ["::1:21336", "127.0.0.1", "example:port"] {
+            let message = parse_bind(bind).unwrap_err().to_string();
+            assert!(message.contains(&format!("{:?}", bind)), "{}", message);
+            assert!(message.contains("`[::1]:21336`"), "{}", message);
+        }
+    }
+}
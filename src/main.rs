@@ -1,117 +1,25 @@
-use crate::cloudflare::ApiRequest;
-use crate::datastructures::Config;
-use crate::file_watcher::FileWatchDog;
-use crate::web::{get, get_debug, post};
-use axum::http::StatusCode;
-use axum::{Extension, Json, Router};
+use cautious_waffle::{Config, RunOptions};
 use clap::{arg, command};
-use log::{debug, error, info, warn, LevelFilter};
-use serde_json::json;
-use std::hint::unreachable_unchecked;
+use log::{info, LevelFilter};
 use std::io::Write;
-use std::sync::atomic::AtomicBool;
-use std::sync::Arc;
-use tap::TapFallible;
-use tokio::sync::RwLock;
-use tower::ServiceBuilder;
-use tower_http::trace::TraceLayer;
-
-mod cloudflare;
-mod datastructures;
-mod file_watcher;
-mod web;
+use std::path::Path;
 
 const DEFAULT_CONFIG_LOCATION: &str = "config.toml";
-
-async fn async_main(
-    config_location: String,
-    file_watchdog: bool,
-    query_enabled: bool,
-) -> anyhow::Result<()> {
-    let config = Config::try_from_file(&config_location).await?;
-
-    let bind = config.get_bind();
-    info!("Version: {}", env!("CARGO_PKG_VERSION"));
-    debug!("Server bind to {}", &bind);
-
-    let query_enabled = query_enabled || config.enable_query();
-
-    let request = ApiRequest::try_from(config)?;
-
-    if request.is_relay() {
-        debug!("Server is running on relay mode");
-    }
-
-    let relay_flag = Arc::new(AtomicBool::new(request.is_relay()));
-    let request = Arc::new(RwLock::new(request));
-
-    let router = Router::new()
-        .route("/:sub_id", axum::routing::get(get).post(post))
-        .route(
-            "/",
-            axum::routing::get(|| async {
-                Json(json!({ "version": env!("CARGO_PKG_VERSION"), "status": 200 }))
-            }),
-        )
-        .fallback(|| async { (StatusCode::FORBIDDEN, "403 Forbidden") })
-        .with_state(request.clone())
-        .layer(Extension(relay_flag.clone()))
-        .layer(ServiceBuilder::new().layer(TraceLayer::new_for_http()));
-
-    let router = if query_enabled {
-        if !std::env::var("DISABLE_QUERY_WARNING")
-            .map(|v| v.eq("1"))
-            .unwrap_or_default()
-        {
-            warn!("Route query is enabled, it may cause some security issue. Set DISABLE_QUERY_WARNING=1 to disable this warning.");
-        }
-        router.route("/query", axum::routing::get(get_debug))
-    } else {
-        router
-    };
-
-    let server_handler = axum_server::Handle::new();
-    let server = tokio::spawn(
-        axum_server::bind(bind.parse().unwrap())
-            .handle(server_handler.clone())
-            .serve(router.into_make_service()),
-    );
-
-    let file_watcher_handler = if file_watchdog {
-        Some(FileWatchDog::start(config_location, request, relay_flag))
-    } else {
-        None
-    };
-
-    tokio::select! {
-        _ = async {
-            tokio::signal::ctrl_c().await.unwrap();
-            info!("Recv Control-C send graceful shutdown command.");
-            server_handler.graceful_shutdown(None);
-            tokio::signal::ctrl_c().await.unwrap();
-            warn!("Force to exit!");
-            std::process::exit(137)
-        } => {
-            unsafe { unreachable_unchecked() }
-        },
-        ret = server => {
-            ret??;
-        }
-    }
-
-    if file_watchdog {
-        tokio::task::spawn_blocking(|| file_watcher_handler.unwrap().stop())
-            .await
-            .tap_err(|e| {
-                error!(
-                    "[Can be safely ignored] Unable to spawn stop file watcher thread {:?}",
-                    e
-                )
-            })
-            .ok();
+const EXAMPLE_CONFIG: &str = include_str!("../config.toml.default");
+
+// Relative path not found in working directory is resolved against directory of executable,
+// e.g. launched by systemd without WorkingDirectory
+fn resolve_config_location(location: String) -> String {
+    let path = Path::new(&location);
+    if path.is_absolute() || path.exists() {
+        return location;
     }
-
-    Ok(())
+    std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(|dir| dir.join(path)))
+        .filter(|path| path.exists())
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or(location)
 }
 
 fn main() -> anyhow::Result<()> {
@@ -122,30 +30,66 @@ fn main() -> anyhow::Result<()> {
             arg!(--systemd "Disable log output in systemd"),
             arg!(--"disable-watcher" "Disable configuration file watcher"),
             arg!(--"enable-query" "Enable query response"),
+            arg!(--"verify-token" "Verify cloudflare token on startup"),
+            arg!(--"check-relay" "Probe relay targets on startup"),
+            arg!(--"strict-relay-check" "Refuse to start if no relay target is reachable"),
+            arg!(--"watcher-mode" [mode] "Configuration file watcher mode")
+                .value_parser(["native", "poll"]),
+            arg!(--"generate-config" "Print example configure file and exit"),
+            arg!(--"log-level" [level] "Default log level, RUST_LOG take precedence")
+                .value_parser(["off", "error", "warn", "info", "debug", "trace"]),
         ])
         .get_matches();
 
-    let mut binding = env_logger::Builder::from_default_env();
+    if matches.get_flag("generate-config") {
+        print!("{}", EXAMPLE_CONFIG);
+        return Ok(());
+    }
+
+    let config_location = resolve_config_location(
+        matches
+            .get_one("config")
+            .map(|s: &String| s.to_string())
+            .unwrap(),
+    );
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    // Configure is loaded before logger is initialized, log level may be specified in it
+    let config = runtime.block_on(Config::try_from_file(&config_location))?;
+
+    let mut binding = env_logger::Builder::new();
+    if let Some(level) = matches
+        .get_one::<String>("log-level")
+        .and_then(|level| level.parse().ok())
+        .or(config.log_level())
+    {
+        binding.filter_level(level);
+    }
+    // Parse environment after default filters, so user specified level takes precedence
     binding
         .filter_module("rustls", LevelFilter::Warn)
         .filter_module("reqwest", LevelFilter::Warn)
         .filter_module("h2", LevelFilter::Warn)
-        .filter_module("hyper::proto::h1", LevelFilter::Warn);
+        .filter_module("hyper::proto::h1", LevelFilter::Warn)
+        .parse_default_env();
     if matches.get_flag("systemd") {
         binding.format(|buf, record| writeln!(buf, "[{}] {}", record.level(), record.args()));
     }
     binding.init();
-
-    tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .build()
-        .unwrap()
-        .block_on(async_main(
-            matches
-                .get_one("config")
-                .map(|s: &String| s.to_string())
-                .unwrap(),
-            !matches.get_flag("disable-watcher"),
-            matches.get_flag("enable-query"),
-        ))
+    info!("Configure file: {}", config_location);
+
+    runtime.block_on(cautious_waffle::run(
+        config,
+        RunOptions {
+            config_location: (!matches.get_flag("disable-watcher")).then_some(config_location),
+            query_enabled: matches.get_flag("enable-query"),
+            verify_token: matches.get_flag("verify-token"),
+            watcher_mode: matches.get_one::<String>("watcher-mode").cloned(),
+            check_relay: matches.get_flag("check-relay"),
+            strict_relay_check: matches.get_flag("strict-relay-check"),
+            bound_addr: None,
+        },
+    ))
 }
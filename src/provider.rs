@@ -0,0 +1,35 @@
+mod v1 {
+    use crate::cloudflare::ApiError;
+    use crate::datastructures::ZoneMapper;
+    use std::future::Future;
+
+    // Boundary of DNS provider, backup provider (e.g. other DNS hosting) is added by implementing
+    // this trait and adding variant to `Provider` in configure
+    pub trait DnsProvider {
+        type Record: Send;
+
+        fn name(&self) -> &'static str;
+
+        // Record of zone domain with record type, None if not exists
+        fn fetch(
+            &self,
+            zone: &ZoneMapper,
+            record_type: &str,
+        ) -> impl Future<Output = Result<Option<Self::Record>, ApiError>> + Send;
+
+        // Address of fetched record
+        fn content(record: &Self::Record) -> &str;
+
+        // Update fetched record to content, or create new record if not exists,
+        // false if provider reports record is not modified
+        fn update(
+            &self,
+            zone: &ZoneMapper,
+            record_type: &str,
+            record: Option<Self::Record>,
+            content: &str,
+        ) -> impl Future<Output = Result<bool, ApiError>> + Send;
+    }
+}
+
+pub use v1::*;
@@ -1,64 +1,331 @@
 pub mod v1 {
-    use crate::cloudflare::ApiRequest;
-    use crate::datastructures::PostData;
-    use axum::extract::{Path, State};
-    use axum::http::StatusCode;
-    use axum::response::IntoResponse;
-    use axum::{Extension, Json};
+    use crate::cloudflare::{ApiError, ApiRequest, UpdateResult, UpdateStatus, REQUEST_ID_HEADER};
+    use crate::datastructures::{BatchEntry, MissingHeader, PostData};
+    use axum::body::{Bytes, StreamBody};
+    use axum::extract::rejection::{BytesRejection, JsonRejection};
+    use axum::extract::{ConnectInfo, Path, Query, State};
+    use axum::http::{header, HeaderValue, Method, Request, StatusCode, Uri};
+    use axum::middleware::Next;
+    use axum::response::{IntoResponse, Response};
+    use axum::Json;
+    use base64::{engine::general_purpose, Engine as _};
+    use futures_util::StreamExt;
     use headers::HeaderMap;
-    use log::{info, warn};
-    use std::str::FromStr;
-    use std::sync::atomic::{AtomicBool, Ordering};
-    use std::sync::Arc;
+    use log::{debug, info, log, warn};
+    use serde_derive::Deserialize;
+    use serde_json::json;
+    use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Instant, SystemTime, UNIX_EPOCH};
     use tap::TapFallible;
     use tokio::sync::RwLock;
 
-    const BAD_REQUEST: (StatusCode, &str) = (StatusCode::BAD_REQUEST, "400 Bad request\n");
-    const FORBIDDEN: (StatusCode, &str) = (StatusCode::FORBIDDEN, "403 Forbidden\n");
-    const SERVICE_UNAVAILABLE: (StatusCode, &str) = (
-        StatusCode::SERVICE_UNAVAILABLE,
-        "500 Services Unavailable\n",
-    );
     const OK: (StatusCode, &str) = (StatusCode::OK, "200 OK\n");
+    const TIMESTAMP_HEADER: &str = "X-Timestamp";
+    const SIGNATURE_HEADER: &str = "X-Signature";
+    const SIGNATURE_WINDOW_SECS: u64 = 300;
+    const ACCEPTED: (StatusCode, &str) = (StatusCode::ACCEPTED, "202 Accepted\n");
+    const REQUEST_ID_MAX_LEN: usize = 128;
+    const FORCE_UPDATE_HEADER: &str = "X-Force-Update";
+
+    fn accept_json(headers: &HeaderMap) -> bool {
+        headers
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.contains("application/json"))
+            .unwrap_or_default()
+    }
+
+    // Body rejected by extractor is responded in same shape of other errors
+    fn body_rejection(status: StatusCode, json: bool) -> Response {
+        if status == StatusCode::PAYLOAD_TOO_LARGE {
+            ApiError::PayloadTooLarge.into_response_with(json)
+        } else {
+            ApiError::BadRequest.into_response_with(json)
+        }
+    }
+
+    // Token bucket shared by all clients, burst is same as rate (one second)
+    #[derive(Debug)]
+    pub struct RateLimiter {
+        rate: f64,
+        // Available tokens and last refill time
+        bucket: Mutex<(f64, Instant)>,
+    }
+
+    impl RateLimiter {
+        pub fn new(rate: u32) -> Self {
+            Self {
+                rate: rate as f64,
+                bucket: Mutex::new((rate as f64, Instant::now())),
+            }
+        }
+
+        fn try_acquire(&self) -> bool {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let tokens =
+                (bucket.0 + now.duration_since(bucket.1).as_secs_f64() * self.rate).min(self.rate);
+            if tokens < 1.0 {
+                *bucket = (tokens, now);
+                return false;
+            }
+            *bucket = (tokens - 1.0, now);
+            true
+        }
+    }
+
+    pub async fn global_rate_limit<B>(
+        State(limiter): State<Arc<RateLimiter>>,
+        request: Request<B>,
+        next: Next<B>,
+    ) -> Response {
+        if !limiter.try_acquire() {
+            return ApiError::TooManyRequests.into_response_with(accept_json(request.headers()));
+        }
+        next.run(request).await
+    }
+
+    // Reuse incoming request ID (e.g. from downstream relay), generate new one if absent or invalid
+    fn request_id(headers: &HeaderMap) -> String {
+        if let Some(id) = headers
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| !v.is_empty() && v.len() <= REQUEST_ID_MAX_LEN)
+        {
+            return id.to_string();
+        }
+        let mut id = [0u8; 16];
+        ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut id).ok();
+        hex::encode(id)
+    }
+
+    // X-Forwarded-For may contain proxy chain, last one is appended by nearest proxy,
+    // leftmost entries are controlled by client and can't be trusted,
+    // surrounding whitespace and quotes added by some proxies are stripped
+    fn header_ip(value: &str) -> &str {
+        value
+            .rsplit(',')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .trim_matches('"')
+            .trim()
+    }
+
+    fn bearer_token(headers: &HeaderMap) -> &str {
+        headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .unwrap_or_default()
+    }
+
+    // `X-Force-Update: 1` bypasses unchanged check, only accepted from client with
+    // `hmac_key` (signature is verified before) or with admin token
+    fn force_update(api: &ApiRequest, id: &str, headers: &HeaderMap) -> Result<bool, ApiError> {
+        let force = headers
+            .get(FORCE_UPDATE_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| matches!(v.trim(), "1" | "true"));
+        if !force {
+            return Ok(false);
+        }
+        if api.has_hmac_key(id) || api.check_admin_token(bearer_token(headers)) {
+            return Ok(true);
+        }
+        warn!(
+            "{} force update is rejected, request is not authenticated",
+            id
+        );
+        Err(ApiError::forbidden())
+    }
+
+    // Resolve client IP from TCP peer address or configured header columns,
+    // first valid IP is used, fallback to first present value (rejected later)
+    fn source_ip(
+        api: &ApiRequest,
+        id: &str,
+        headers: &HeaderMap,
+        peer: &SocketAddr,
+    ) -> Option<String> {
+        if api.use_peer_addr() {
+            return Some(peer.ip().to_string());
+        }
+        let values = api
+            .column(id)
+            .iter()
+            .filter_map(|column| headers.get(column))
+            .filter_map(|ip| {
+                ip.to_str()
+                    .tap_err(|e| warn!("Convert header value error: {:?}", e))
+                    .ok()
+            })
+            .map(header_ip)
+            .collect::<Vec<_>>();
+        values
+            .iter()
+            .find(|ip| ip.parse::<IpAddr>().is_ok())
+            .or(values.first())
+            .map(|ip| ip.to_string())
+    }
+
+    // IPv6 address from `column_ipv6` of dual-stack client, None if not configured or absent
+    fn source_ipv6(
+        api: &ApiRequest,
+        id: &str,
+        headers: &HeaderMap,
+    ) -> Result<Option<String>, ApiError> {
+        if api.use_peer_addr() || !api.accept_ipv6(id) {
+            return Ok(None);
+        }
+        let Some(value) = api
+            .column_ipv6()
+            .iter()
+            .find_map(|column| headers.get(column))
+        else {
+            return Ok(None);
+        };
+        let ip = value.to_str().map(header_ip).unwrap_or_default();
+        if ip.parse::<Ipv6Addr>().is_err() {
+            warn!("{} IPv6 column value {:?} is not IPv6 address", id, ip);
+            return Err(ApiError::BadRequest);
+        }
+        Ok(Some(ip.to_string()))
+    }
+
+    // Client with `hmac_key` should send `X-Timestamp` (unix seconds) and `X-Signature`,
+    // which is hex encoded HMAC-SHA256 of `{method}\n{path}\n{timestamp}\n{body}`,
+    // method and path are signed so signature can't be replayed to other route (e.g. DELETE)
+    fn check_signature(
+        api: &ApiRequest,
+        id: &str,
+        method: &Method,
+        uri: &Uri,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Result<(), ApiError> {
+        if !api.has_hmac_key(id) {
+            return Ok(());
+        }
+        let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+
+        let timestamp = header(TIMESTAMP_HEADER).ok_or_else(ApiError::forbidden)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        // Replay protection
+        if !timestamp
+            .parse::<i64>()
+            .is_ok_and(|t| now.abs_diff(t) <= SIGNATURE_WINDOW_SECS)
+        {
+            warn!(
+                "{} signature timestamp {:?} is out of window",
+                id, timestamp
+            );
+            return Err(ApiError::forbidden());
+        }
+
+        let signature = header(SIGNATURE_HEADER)
+            .and_then(|s| hex::decode(s).ok())
+            .ok_or_else(ApiError::forbidden)?;
+        let message = [
+            method.as_str().as_bytes(),
+            b"\n",
+            uri.path().as_bytes(),
+            b"\n",
+            timestamp.as_bytes(),
+            b"\n",
+            body,
+        ]
+        .concat();
+        if !api.verify_signature(id, &message, &signature) {
+            warn!("{} signature mismatch", id);
+            return Err(ApiError::forbidden());
+        }
+        Ok(())
+    }
 
     pub async fn get(
         Path(id): Path<String>,
+        ConnectInfo(peer): ConnectInfo<SocketAddr>,
+        method: Method,
+        uri: Uri,
         headers: HeaderMap,
         State(api): State<Arc<RwLock<ApiRequest>>>,
-        Extension(relay_status): Extension<Arc<AtomicBool>>,
     ) -> impl IntoResponse {
-        let post_data = if relay_status.load(Ordering::Relaxed) {
-            let api = api.read().await;
-            headers
-                .get(api.column())
-                .map(|ip| {
-                    ip.to_str()
-                        .tap_err(|e| warn!("Convert header value error: {:?}", e))
-                        .ok()
-                })
-                .flatten()
-                .map(|ip| PostData::new(ip.to_string()))
+        // Same guard is used for whole request, reload can't change relay mode halfway
+        let api = api.read().await;
+        if let Err(e) = check_signature(&api, &id, &method, &uri, &headers, &[]) {
+            return e.into_response_with(accept_json(&headers));
+        }
+        let post_data = if api.is_relay() {
+            source_ip(&api, &id, &headers, &peer).map(PostData::new)
         } else {
             None
         };
+        let ipv6 = match source_ipv6(&api, &id, &headers) {
+            Ok(ipv6) => ipv6,
+            Err(e) => return e.into_response_with(accept_json(&headers)),
+        };
+        if api.is_relay() && post_data.is_none() && ipv6.is_none() {
+            match api.relay_missing_header() {
+                MissingHeader::Lenient => {
+                    debug!("{} IP header is missing, skip update", id);
+                    return OK.into_response();
+                }
+                MissingHeader::Strict => debug!("{} IP header is missing, reject", id),
+            }
+        }
+
+        staff(id, post_data, ipv6, &api, headers, peer).await
+    }
 
-        staff(id, post_data, api, headers).await
+    fn header_value_to_json(value: &HeaderValue) -> serde_json::Value {
+        match value.to_str() {
+            Ok(s) => serde_json::Value::from(s),
+            Err(_) => json!({ "base64": general_purpose::STANDARD.encode(value.as_bytes()) }),
+        }
     }
 
-    pub async fn get_debug(mut headers: HeaderMap) -> impl IntoResponse {
+    pub async fn get_debug(
+        ConnectInfo(remote): ConnectInfo<SocketAddr>,
+        method: Method,
+        uri: Uri,
+        headers: HeaderMap,
+        State(api): State<Arc<RwLock<ApiRequest>>>,
+    ) -> impl IntoResponse {
+        let api = api.read().await;
         let mut map = serde_json::Map::new();
-        for header in headers.drain() {
-            if let Some(name) = header.0 {
-                map.insert(
-                    name.to_string(),
-                    serde_json::Value::from(header.1.to_str().ok()),
-                );
+        for name in headers.keys() {
+            if api.is_redacted_header(name.as_str()) {
+                map.insert(name.to_string(), serde_json::Value::from("<redacted>"));
+                continue;
             }
+            let mut values = headers
+                .get_all(name)
+                .iter()
+                .map(header_value_to_json)
+                .collect::<Vec<_>>();
+            // Repeated header will be displayed as array
+            let value = if values.len() == 1 {
+                values.pop().unwrap()
+            } else {
+                serde_json::Value::from(values)
+            };
+            map.insert(name.to_string(), value);
         }
 
         (
             [("content-type", "application/json")],
-            serde_json::to_string_pretty(&map).unwrap(),
+            serde_json::to_string_pretty(&json!({
+                "method": method.as_str(),
+                "path": uri.path(),
+                "remote_addr": remote.to_string(),
+                "headers": map,
+            }))
+            .unwrap(),
         )
     }
 
@@ -66,68 +333,371 @@ pub mod v1 {
     // Post data { "ip": "114.51.4.19" } to server
     pub async fn post(
         Path(id): Path<String>,
+        ConnectInfo(peer): ConnectInfo<SocketAddr>,
+        method: Method,
+        uri: Uri,
         State(api): State<Arc<RwLock<ApiRequest>>>,
         headers: HeaderMap,
-        Json(data): Json<PostData>,
+        body: Result<Bytes, BytesRejection>,
     ) -> impl IntoResponse {
-        staff(id, Some(data), api, headers).await
+        let json = accept_json(&headers);
+        let body = match body {
+            Ok(body) => body,
+            Err(e) => {
+                debug!("Unable read body of {}: {}", id, e);
+                return body_rejection(e.status(), json);
+            }
+        };
+        let api = api.read().await;
+        // Raw body is required by signature verification
+        if let Err(e) = check_signature(&api, &id, &method, &uri, &headers, &body) {
+            return e.into_response_with(json);
+        }
+        let data = match serde_json::from_slice::<PostData>(&body) {
+            Ok(data) => data,
+            Err(e) => {
+                // e.g. missing field `ip` (`address` and `ip_address` are also accepted)
+                debug!("Unable parse body of {}: {}", id, e);
+                return ApiError::BadRequest.into_response_with(json);
+            }
+        };
+        staff(id, Some(data), None, &api, headers, peer).await
+    }
+
+    #[derive(Deserialize)]
+    pub struct AdminConfigQuery {
+        format: Option<String>,
+    }
+
+    // Default to NDJSON: first line is global settings, then one line per client mapping
+    pub async fn admin_config(
+        headers: HeaderMap,
+        Query(query): Query<AdminConfigQuery>,
+        State(api): State<Arc<RwLock<ApiRequest>>>,
+    ) -> Response {
+        let api = api.read().await;
+        if !api.check_admin_token(bearer_token(&headers)) {
+            return ApiError::forbidden().into_response_with(accept_json(&headers));
+        }
+        match query.format.as_deref() {
+            Some("json") => return Json(api.effective_config()).into_response(),
+            None | Some("ndjson") => {}
+            Some(_) => return ApiError::BadRequest.into_response_with(accept_json(&headers)),
+        }
+        let settings = api.effective_settings();
+        // Client values are collected under read guard, serialized lazily after lock is released
+        let clients = api
+            .client_ids()
+            .into_iter()
+            .filter_map(|uuid| {
+                api.effective_client(&uuid).map(|mut client| {
+                    client["uuid"] = uuid.into();
+                    client
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(api);
+        let lines =
+            futures_util::stream::iter(std::iter::once(settings).chain(clients)).map(|line| {
+                serde_json::to_vec(&line).map(|mut line| {
+                    line.push(b'\n');
+                    line
+                })
+            });
+        (
+            [(header::CONTENT_TYPE, "application/x-ndjson")],
+            StreamBody::new(lines),
+        )
+            .into_response()
+    }
+
+    pub async fn delete(
+        Path(id): Path<String>,
+        method: Method,
+        uri: Uri,
+        headers: HeaderMap,
+        State(api): State<Arc<RwLock<ApiRequest>>>,
+    ) -> Response {
+        let json = accept_json(&headers);
+        let api = api.read().await;
+        if !api.is_valid_id(&id) {
+            return ApiError::BadRequest.into_response_with(json);
+        }
+        if let Err(e) = check_signature(&api, &id, &method, &uri, &headers, &[]) {
+            return e.into_response_with(json);
+        }
+
+        let ret = tokio::time::timeout(api.request_timeout(), api.delete(&id))
+            .await
+            .map_err(|_| {
+                warn!("{} delete timeout", id);
+                ApiError::GatewayTimeout
+            })
+            .and_then(|ret| ret);
+        match ret {
+            Ok(()) => OK.into_response(),
+            Err(e) => e.into_response_with(json),
+        }
+    }
+
+    // To use this batch function
+    // Post data [{ "uuid": "...", "ip": "114.51.4.19" }] to /batch
+    pub async fn batch(
+        State(api): State<Arc<RwLock<ApiRequest>>>,
+        headers: HeaderMap,
+        // Entries are parsed one by one, so malformed entry doesn't reject whole batch
+        entries: Result<Json<Vec<serde_json::Value>>, JsonRejection>,
+    ) -> Response {
+        let entries = match entries {
+            Ok(Json(entries)) => entries,
+            Err(e) => {
+                debug!("Unable parse batch body: {}", e);
+                return body_rejection(e.status(), accept_json(&headers));
+            }
+        };
+        let api = api.read().await;
+        let api = &*api;
+        let request_id = request_id(&headers);
+        let request_id = request_id.as_str();
+
+        // Results are kept in input order
+        let results = futures_util::stream::iter(entries)
+            .map(|entry| async move {
+                let uuid = entry
+                    .get("uuid")
+                    .and_then(|v| v.as_str())
+                    .map(str::to_string);
+                let entry = match serde_json::from_value::<BatchEntry>(entry) {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        debug!("Unable parse batch entry (request {}): {}", request_id, e);
+                        // Parse error only describes submitted entry, safe to be returned
+                        return json!({
+                            "uuid": uuid,
+                            "status": ApiError::BadRequest.status_code().as_u16(),
+                            "error": ApiError::BadRequest.kind(),
+                            "message": e.to_string(),
+                        });
+                    }
+                };
+                // Batch entry can't be signed
+                let ret = if api.has_hmac_key(entry.uuid()) {
+                    Err(ApiError::forbidden())
+                } else {
+                    check_and_request(api, entry.uuid(), entry.ip().to_string(), request_id, false)
+                        .await
+                };
+                match ret {
+                    Ok(result) => {
+                        let status = result.status();
+                        match status {
+                            UpdateStatus::Updated => {
+                                log!(
+                                    api.update_log_level(),
+                                    "{} IP updated (via batch, request {})",
+                                    entry.uuid(),
+                                    request_id
+                                )
+                            }
+                            UpdateStatus::Unchanged => {
+                                log!(
+                                    api.unchanged_log_level(),
+                                    "{} IP unchanged (via batch, request {})",
+                                    entry.uuid(),
+                                    request_id
+                                )
+                            }
+                            UpdateStatus::Queued => {}
+                        }
+                        let code = if status == UpdateStatus::Queued {
+                            StatusCode::ACCEPTED
+                        } else {
+                            StatusCode::OK
+                        };
+                        json!({
+                            "uuid": entry.uuid(),
+                            "status": code.as_u16(),
+                            "updated": status == UpdateStatus::Updated,
+                            "zones": result.zones(),
+                        })
+                    }
+                    Err(e) => json!({
+                        "uuid": entry.uuid(),
+                        "status": e.status_code().as_u16(),
+                        "error": e.kind(),
+                        "message": e.message(),
+                    }),
+                }
+            })
+            .buffered(api.batch_concurrency())
+            .collect::<Vec<_>>()
+            .await;
+
+        Json(results).into_response()
+    }
+
+    async fn check_and_request(
+        api: &ApiRequest,
+        id: &str,
+        ip: String,
+        request_id: &str,
+        force: bool,
+    ) -> Result<UpdateResult, ApiError> {
+        // Check identifier validity
+        if !api.is_valid_id(id) {
+            return Err(ApiError::BadRequest);
+        }
+
+        if !api.is_allowed(id, &ip) {
+            warn!("{} IP {} is not in allowed CIDRs", id, ip);
+            return Err(ApiError::forbidden());
+        }
+
+        tokio::time::timeout(
+            api.request_timeout(),
+            api.request(&id.to_string(), ip, request_id, force),
+        )
+        .await
+        .map_err(|_| {
+            warn!("{} request timeout (request {})", id, request_id);
+            ApiError::GatewayTimeout
+        })?
     }
 
     async fn staff(
         id: String,
         data: Option<PostData>,
-        api: Arc<RwLock<ApiRequest>>,
+        ipv6: Option<String>,
+        api: &ApiRequest,
         headers: HeaderMap,
-    ) -> impl IntoResponse {
-        // Check uuid validity
-        if uuid::Uuid::from_str(&id).is_err() {
-            return BAD_REQUEST;
-        }
+        peer: SocketAddr,
+    ) -> Response {
+        let json = accept_json(&headers);
+        let request_id = request_id(&headers);
 
-        // Configure file
-        let api = api.read().await;
+        // Check identifier validity
+        if !api.is_valid_id(&id) {
+            return ApiError::BadRequest.into_response_with(json);
+        }
 
-        // Get header IP (if empty maybe that's post)
-        let header_ip = if let Some(ip) = headers
-            .get(api.column())
-            .map(|v| v.to_str().unwrap_or_default().to_string())
-        {
-            ip
-        } else {
-            String::new()
+        let force = match force_update(api, &id, &headers) {
+            Ok(force) => force,
+            Err(e) => return e.into_response_with(json),
         };
 
+        // Get header (or peer) IP (if empty maybe that's post)
+        let header_ip = source_ip(api, &id, &headers, &peer).unwrap_or_default();
+
         // Check is ip from post
-        let ret = match data {
+        let ip = match data {
             None => {
-                if header_ip.is_empty() {
-                    return FORBIDDEN;
+                if header_ip.is_empty() && ipv6.is_none() {
+                    if api.allow_lookup() {
+                        let ret = tokio::time::timeout(api.request_timeout(), api.lookup(&id))
+                            .await
+                            .map_err(|_| {
+                                warn!("{} lookup timeout (request {})", id, request_id);
+                                ApiError::GatewayTimeout
+                            })
+                            .and_then(|ret| ret);
+                        return match ret {
+                            Ok(records) => Json(records).into_response(),
+                            Err(e) => e.into_response_with(json),
+                        };
+                    }
+                    return ApiError::forbidden().into_response_with(json);
                 }
-                api.request(&id, header_ip.clone()).await
+                header_ip.clone()
             }
-            Some(ref data) => api.request(&id, data.ip().to_string()).await,
+            Some(ref data) => data.ip().to_string(),
         };
 
-        match ret {
-            Ok(ret) => {
-                if ret {
-                    if !header_ip.is_empty() && data.is_none() {
-                        info!("{} IP updated (via {})", id, header_ip);
-                    } else {
-                        info!("{} IP updated", id);
-                    }
-                }
-                // Check is relay and is success
-                if !(api.is_relay() && !ret) {
-                    OK
+        // Dual-stack client updates both families, family without header is skipped
+        let mut result: Option<UpdateResult> = None;
+        for ip in [Some(ip).filter(|ip| !ip.is_empty()), ipv6]
+            .into_iter()
+            .flatten()
+        {
+            let ret = match check_and_request(api, &id, ip, &request_id, force).await {
+                Ok(ret) => ret,
+                Err(e) => return e.into_response_with(json),
+            };
+            result = Some(match result {
+                Some(result) => result.merge(ret),
+                None => ret,
+            });
+        }
+        let Some(result) = result else {
+            return ApiError::BadRequest.into_response_with(json);
+        };
+        let response = match result.status() {
+            UpdateStatus::Updated => {
+                if !header_ip.is_empty() && data.is_none() {
+                    log!(
+                        api.update_log_level(),
+                        "{} IP updated (via {}, request {})",
+                        id,
+                        header_ip,
+                        request_id
+                    );
                 } else {
-                    SERVICE_UNAVAILABLE
+                    log!(
+                        api.update_log_level(),
+                        "{} IP updated (request {})",
+                        id,
+                        request_id
+                    );
                 }
+                OK
             }
-            Err(e) => e.into_response(),
+            UpdateStatus::Unchanged => {
+                log!(
+                    api.unchanged_log_level(),
+                    "{} IP unchanged (request {})",
+                    id,
+                    request_id
+                );
+                OK
+            }
+            UpdateStatus::Queued => {
+                info!("{} relay forward queued (request {})", id, request_id);
+                ACCEPTED
+            }
+        };
+        if json {
+            // Per zone result for multi-zone clients
+            return (
+                response.0,
+                Json(json!({
+                    "status": response.0.as_u16(),
+                    "updated": result.status() == UpdateStatus::Updated,
+                    "zones": result.zones(),
+                })),
+            )
+                .into_response();
+        }
+        response.into_response()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::header_ip;
+
+        #[test]
+        fn test_header_ip() {
+            assert_eq!(header_ip("1.2.3.4"), "1.2.3.4");
+            assert_eq!(header_ip(" 1.2.3.4 "), "1.2.3.4");
+            assert_eq!(header_ip("\"1.2.3.4\""), "1.2.3.4");
+            assert_eq!(header_ip(" \" 1.2.3.4 \" "), "1.2.3.4");
+            // Last one of proxy chain is appended by proxy, spoofed entries are ignored
+            assert_eq!(header_ip(" 10.0.0.1 , 1.2.3.4"), "1.2.3.4");
+            assert_eq!(header_ip("10.0.0.1, \"2606:4700::1\""), "2606:4700::1");
+            assert_eq!(header_ip("8.8.8.8,1.2.3.4"), "1.2.3.4");
         }
     }
 }
 
-pub use current::{get, get_debug, post};
+pub use current::{
+    admin_config, batch, delete, get, get_debug, global_rate_limit, post, RateLimiter,
+};
 pub use v1 as current;